@@ -0,0 +1,3 @@
+ALTER TABLE versions ADD COLUMN yanked BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE UNIQUE INDEX IF NOT EXISTS versions_package_version_idx ON versions("package", "version");
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS tokens
+(
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "owner" INTEGER NOT NULL,
+    "name" varchar(50) NOT NULL,
+    "hash" varchar(64) UNIQUE NOT NULL,
+    "scopes" TEXT NOT NULL,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "last_used_at" TEXT
+);
+
+CREATE INDEX token_owner_idx ON tokens("owner");
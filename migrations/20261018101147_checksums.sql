@@ -0,0 +1,2 @@
+ALTER TABLE versions ADD COLUMN sha256 varchar(64);
+ALTER TABLE versions ADD COLUMN size INTEGER;
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS signing_keys
+(
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "owner" INTEGER NOT NULL,
+    "name" varchar(50) NOT NULL,
+    "public_key" varchar(44) NOT NULL,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "revoked_at" TEXT
+);
+
+CREATE INDEX signing_key_owner_idx ON signing_keys("owner");
+
+ALTER TABLE versions ADD COLUMN signature varchar(88);
+ALTER TABLE versions ADD COLUMN signature_kind varchar(10);
+ALTER TABLE versions ADD COLUMN signing_key INTEGER REFERENCES signing_keys("id");
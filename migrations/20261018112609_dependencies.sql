@@ -0,0 +1,7 @@
+ALTER TABLE versions ADD COLUMN dependencies TEXT NOT NULL DEFAULT '{}';
+ALTER TABLE versions ADD COLUMN platform TEXT NOT NULL DEFAULT '{}';
+
+-- Split the mixed requirements of existing versions by whether they name a package
+UPDATE versions SET
+    dependencies = (SELECT json_group_object(r."key", r."value") FROM json_each(COALESCE(versions."requires", '{}')) r WHERE r."key" IN (SELECT "name" FROM packages)),
+    platform = (SELECT json_group_object(r."key", r."value") FROM json_each(COALESCE(versions."requires", '{}')) r WHERE r."key" NOT IN (SELECT "name" FROM packages));
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS version_dependencies
+(
+    "version" INTEGER REFERENCES versions("id") NOT NULL,
+    "dependency" varchar(30) NOT NULL,
+    "requirement" TEXT NOT NULL,
+    PRIMARY KEY ("version", "dependency")
+);
+
+CREATE INDEX version_dependencies_dependency_idx ON version_dependencies("dependency");
+
+INSERT INTO version_dependencies ("version", "dependency", "requirement")
+    SELECT v."id", d."key", d."value" FROM versions v, json_each(v."dependencies") d;
@@ -0,0 +1,21 @@
+ALTER TABLE versions ADD COLUMN readme_text TEXT;
+
+CREATE VIRTUAL TABLE IF NOT EXISTS package_search USING fts5("name", "short_description", "author", "readme");
+
+INSERT INTO package_search (rowid, "name", "short_description", "author", "readme")
+    SELECT v."id", p."name", v."short_description", v."author", COALESCE(v."readme_text", '') FROM versions v JOIN packages p ON (v."package"=p."id");
+
+CREATE TRIGGER versions_search_insert AFTER INSERT ON versions BEGIN
+    INSERT INTO package_search (rowid, "name", "short_description", "author", "readme")
+        SELECT NEW."id", p."name", NEW."short_description", NEW."author", COALESCE(NEW."readme_text", '') FROM packages p WHERE p."id"=NEW."package";
+END;
+
+CREATE TRIGGER versions_search_update AFTER UPDATE OF "short_description", "author", "readme_text" ON versions BEGIN
+    DELETE FROM package_search WHERE rowid=OLD."id";
+    INSERT INTO package_search (rowid, "name", "short_description", "author", "readme")
+        SELECT NEW."id", p."name", NEW."short_description", NEW."author", COALESCE(NEW."readme_text", '') FROM packages p WHERE p."id"=NEW."package";
+END;
+
+CREATE TRIGGER versions_search_delete AFTER DELETE ON versions BEGIN
+    DELETE FROM package_search WHERE rowid=OLD."id";
+END;
@@ -0,0 +1 @@
+ALTER TABLE versions ADD COLUMN published_at TEXT;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS download_stats
+(
+    "version" INTEGER REFERENCES versions("id") NOT NULL,
+    "day" TEXT NOT NULL,
+    "count" INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY ("version", "day")
+);
+
+CREATE INDEX download_stats_day_idx ON download_stats("day");
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS version_assets
+(
+    "version" INTEGER REFERENCES versions("id") NOT NULL,
+    "path" TEXT NOT NULL,
+    "content_type" TEXT NOT NULL,
+    "data" BLOB NOT NULL,
+    PRIMARY KEY ("version", "path")
+);
@@ -0,0 +1,6 @@
+ALTER TABLE packages ADD COLUMN webhook_secret TEXT;
+
+CREATE TABLE webhook_deliveries (
+    "delivery" TEXT PRIMARY KEY NOT NULL,
+    "received_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
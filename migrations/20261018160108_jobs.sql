@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS jobs
+(
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "package" INTEGER REFERENCES packages("id") NOT NULL,
+    "delivery" TEXT,
+    "repository" TEXT NOT NULL,
+    "tag" TEXT NOT NULL,
+    "release" TEXT NOT NULL,
+    "status" TEXT NOT NULL DEFAULT 'queued',
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "last_error" TEXT,
+    "run_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "created_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    "updated_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX jobs_due_idx ON jobs("status", "run_at");
+CREATE INDEX jobs_package_idx ON jobs("package");
@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS delivery_log
+(
+    "id" INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    "package" INTEGER REFERENCES packages("id") NOT NULL,
+    "delivery" TEXT,
+    "event" TEXT,
+    "repository" TEXT NOT NULL,
+    "action" TEXT,
+    "status" INTEGER NOT NULL,
+    "outcome" TEXT NOT NULL,
+    "error" TEXT,
+    "job" INTEGER REFERENCES jobs("id"),
+    "payload" TEXT,
+    "redelivery" BOOLEAN NOT NULL DEFAULT FALSE,
+    "received_at" TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX delivery_log_package_idx ON delivery_log("package");
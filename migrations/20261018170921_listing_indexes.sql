@@ -0,0 +1,2 @@
+-- Listings sorted by publishing date read this index instead of every version
+CREATE INDEX versions_published_at_idx ON versions(COALESCE("published_at", ''));
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS version_files
+(
+    "version" INTEGER REFERENCES versions("id") NOT NULL,
+    "path" TEXT NOT NULL,
+    "size" INTEGER NOT NULL,
+    "sha256" varchar(64) NOT NULL,
+    PRIMARY KEY ("version", "path")
+);
@@ -0,0 +1,233 @@
+// Maintenance commands, run as `aopkg <command>` instead of starting the server
+use crate::{
+    db::{self, Digest},
+    description, files, package,
+};
+
+use actix_web::web::Bytes;
+use log::{error, info, warn};
+use semver::Version;
+use sha2::{Digest as _, Sha256};
+use sqlx::SqlitePool;
+use tokio::fs::{read, read_dir};
+
+use std::{collections::HashSet, io::Cursor, path::Path};
+
+/// Runs an admin command. Returns whether it succeeded.
+pub async fn run(command: &str, pool: &SqlitePool) -> bool {
+    match command {
+        "verify" => verify(pool).await,
+        "reindex" => reindex(pool).await,
+        "sanitize" => sanitize(pool).await,
+        _ => {
+            error!(
+                "Unknown command {}, available commands: verify, reindex, sanitize",
+                command
+            );
+            false
+        }
+    }
+}
+
+/// Re-hashes every stored artifact and compares it to the recorded checksum.
+/// Versions published before checksums were recorded get theirs filled in.
+async fn verify(pool: &SqlitePool) -> bool {
+    let artifacts = db::get_all_artifacts(pool).await.expect("DB error");
+    let mut failed = 0;
+
+    for artifact in &artifacts {
+        let version = Version::parse(&artifact.version).unwrap();
+        let path = db::artifact_path(&artifact.name, &version);
+
+        let file = match read(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!(
+                    "{} {}: cannot read {}: {}",
+                    artifact.name,
+                    version,
+                    path.display(),
+                    e
+                );
+                failed += 1;
+                continue;
+            }
+        };
+
+        let digest = Digest {
+            sha256: hex::encode(Sha256::digest(&file)),
+            size: file.len() as i64,
+        };
+
+        match (&artifact.sha256, artifact.size) {
+            (Some(sha256), Some(size)) if *sha256 == digest.sha256 && size == digest.size => {}
+            (Some(sha256), size) => {
+                error!(
+                    "{} {}: checksum mismatch, expected {} ({} bytes), found {} ({} bytes)",
+                    artifact.name,
+                    version,
+                    sha256,
+                    size.unwrap_or_default(),
+                    digest.sha256,
+                    digest.size
+                );
+                failed += 1;
+            }
+            (None, _) => {
+                warn!(
+                    "{} {}: no checksum recorded, storing {}",
+                    artifact.name, version, digest.sha256
+                );
+                db::set_digest(pool, artifact.id, &digest)
+                    .await
+                    .expect("DB error");
+            }
+        }
+    }
+
+    failed += find_orphans(pool).await;
+    info!("Verified {} artifacts, {} failed", artifacts.len(), failed);
+
+    failed == 0
+}
+
+/// Reports stored archives without a published version, which a crash while
+/// publishing leaves behind. They block publishing their version until they
+/// are removed. Runs when the server starts. Returns how many were found.
+pub async fn find_orphans(pool: &SqlitePool) -> usize {
+    let published: HashSet<_> = db::get_all_artifacts(pool)
+        .await
+        .expect("DB error")
+        .iter()
+        .map(|artifact| {
+            db::artifact_path(&artifact.name, &Version::parse(&artifact.version).unwrap())
+        })
+        .collect();
+
+    let mut entries = match read_dir("data").await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Cannot list the stored archives: {}", e);
+            return 0;
+        }
+    };
+    let mut orphans = 0;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = Path::new("data").join(entry.file_name());
+        if path.extension().is_some_and(|ext| ext == "zip") && !published.contains(&path) {
+            error!("{}: no version is published from it", path.display());
+            orphans += 1;
+        }
+    }
+
+    orphans
+}
+
+/// Stores the README text of versions in the search index.
+async fn index(pool: &SqlitePool, descriptions: &[db::StoredDescription]) {
+    for stored in descriptions {
+        db::set_readme_text(pool, stored.id, &description::to_text(&stored.description))
+            .await
+            .expect("DB error");
+    }
+}
+
+/// Rebuilds the README text of every version in the search index from its
+/// stored description.
+async fn reindex(pool: &SqlitePool) -> bool {
+    let descriptions = db::get_all_descriptions(pool).await.expect("DB error");
+    index(pool, &descriptions).await;
+    info!("Reindexed {} versions", descriptions.len());
+
+    true
+}
+
+/// Adds the READMEs of versions that are not in the search index yet, like
+/// those published before it existed. Runs when the server starts.
+pub async fn index_missing(pool: &SqlitePool) {
+    let descriptions = db::get_unindexed_descriptions(pool)
+        .await
+        .expect("DB error");
+    if !descriptions.is_empty() {
+        index(pool, &descriptions).await;
+        info!("Added {} versions to the search index", descriptions.len());
+    }
+}
+
+/// Lists the files of versions published before file listings were stored.
+/// Runs when the server starts.
+pub async fn list_missing_files(pool: &SqlitePool) {
+    let artifacts = db::get_unlisted_artifacts(pool).await.expect("DB error");
+    let mut listed = 0;
+
+    for artifact in &artifacts {
+        let version = Version::parse(&artifact.version).unwrap();
+        match files::list(artifact.name.clone(), version.clone()).await {
+            Ok(Some(entries)) => {
+                db::set_files(pool, artifact.id, &entries)
+                    .await
+                    .expect("DB error");
+                listed += 1;
+            }
+            Ok(None) => warn!("{} {}: no stored archive to list", artifact.name, version),
+            Err(e) => error!("{} {}: cannot list files: {}", artifact.name, version, e),
+        }
+    }
+
+    if listed > 0 {
+        info!("Listed the files of {} versions", listed);
+    }
+}
+
+/// Renders the README of every stored version again with the current
+/// sanitizer and extracts the assets it references. If a package cannot be
+/// read anymore, its stored description is sanitized instead.
+async fn sanitize(pool: &SqlitePool) -> bool {
+    let descriptions = db::get_all_descriptions(pool).await.expect("DB error");
+    let mut changed = 0;
+
+    for stored in &descriptions {
+        let version = Version::parse(&stored.version).unwrap();
+        let path = db::artifact_path(&stored.name, &version);
+
+        let parsed = match read(&path).await {
+            Ok(file) => package::try_parse(Cursor::new(Bytes::from(file)))
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let sanitized = match parsed {
+            Ok(pkg) => {
+                db::set_assets(pool, stored.id, &pkg.assets)
+                    .await
+                    .expect("DB error");
+                pkg.description
+            }
+            Err(e) => {
+                warn!(
+                    "{} {}: cannot render {}, sanitizing the stored description: {}",
+                    stored.name,
+                    version,
+                    path.display(),
+                    e
+                );
+                description::sanitize(&stored.description)
+            }
+        };
+
+        if sanitized != stored.description {
+            db::set_description(pool, stored.id, &sanitized)
+                .await
+                .expect("DB error");
+            changed += 1;
+        }
+    }
+
+    info!(
+        "Sanitized {} versions, {} descriptions changed",
+        descriptions.len(),
+        changed
+    );
+
+    true
+}
@@ -0,0 +1,161 @@
+// Session and API token authentication
+use crate::{db, error::ApiError};
+
+use actix_session::Session;
+use actix_web::{http::header::AUTHORIZATION, web::Data, HttpRequest, HttpResponse};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{ColumnIndex, Decode, Error as SqlxError, FromRow, Row, SqlitePool, Type};
+
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+const TOKEN_PREFIX: &str = "aopkg_";
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    Publish,
+    Yank,
+    ManageWebhooks,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 3] = [Scope::Publish, Scope::Yank, Scope::ManageWebhooks];
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Publish => write!(f, "publish"),
+            Self::Yank => write!(f, "yank"),
+            Self::ManageWebhooks => write!(f, "manage-webhooks"),
+        }
+    }
+}
+
+impl TryFrom<&str> for Scope {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "publish" => Ok(Self::Publish),
+            "yank" => Ok(Self::Yank),
+            "manage-webhooks" => Ok(Self::ManageWebhooks),
+            _ => Err("Unknown scope"),
+        }
+    }
+}
+
+pub fn scopes_to_string(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+#[derive(Serialize)]
+pub struct TokenDb {
+    pub id: i64,
+    pub owner: i64,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+impl<'r, 's, R> FromRow<'r, R> for TokenDb
+where
+    R: Row,
+    &'s str: ColumnIndex<R>,
+    String: Type<R::Database> + Decode<'r, R::Database>,
+    i64: Type<R::Database> + Decode<'r, R::Database>,
+{
+    #[inline]
+    fn from_row(row: &'r R) -> Result<Self, SqlxError> {
+        let scopes_str: String = row.try_get("scopes")?;
+        let scopes = scopes_str
+            .split(',')
+            .filter_map(|s| Scope::try_from(s).ok())
+            .collect();
+
+        Ok(Self {
+            id: row.try_get("id")?,
+            owner: row.try_get("owner")?,
+            name: row.try_get("name")?,
+            scopes,
+            created_at: row.try_get("created_at")?,
+            last_used_at: row.try_get("last_used_at")?,
+        })
+    }
+}
+
+/// Generates a new random API token. Only its hash is ever stored.
+pub fn generate_token() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    format!("{}{}", TOKEN_PREFIX, hex::encode(bytes))
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Resolves the user ID for a mutating request.
+///
+/// An `Authorization: Bearer` header takes precedence over the session and
+/// must carry a token with the given scope. Sessions from the GitHub login
+/// are allowed to do anything. On failure, the response to send is returned.
+pub async fn authorize(
+    req: &HttpRequest,
+    session: &Session,
+    pool: &Data<SqlitePool>,
+    scope: Scope,
+) -> Result<i64, HttpResponse> {
+    if let Some(token) = bearer_token(req) {
+        return match db::get_token_by_hash(pool.clone(), &hash_token(token))
+            .await
+            .expect("DB error")
+        {
+            Some(token) if token.scopes.contains(&scope) => {
+                db::touch_token(pool.clone(), token.id)
+                    .await
+                    .expect("DB error");
+                Ok(token.owner)
+            }
+            Some(_) => Err(HttpResponse::Forbidden().json(ApiError::new(
+                "missing_scope",
+                format!("token lacks the {} scope", scope),
+            ))),
+            None => Err(HttpResponse::Unauthorized()
+                .json(ApiError::new("unauthorized", "invalid or revoked token"))),
+        };
+    }
+
+    match session.get::<i64>("id") {
+        Ok(Some(id)) => Ok(id),
+        _ => Err(HttpResponse::Unauthorized()
+            .json(ApiError::new("unauthorized", "you are not logged in"))),
+    }
+}
+
+#[test]
+fn test_token_hash_is_stable() {
+    let token = generate_token();
+    assert!(token.starts_with(TOKEN_PREFIX));
+    assert_eq!(hash_token(&token), hash_token(&token));
+    assert_ne!(hash_token(&token), hash_token(&generate_token()));
+}
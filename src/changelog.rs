@@ -0,0 +1,93 @@
+// Extracts the release notes of a version from a CHANGELOG.md
+use semver::Version;
+
+/// The level and text of a Markdown heading line, like `(2, "[1.0.0] - 2026-10-18")`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.chars().take_while(|&c| c == '#').count();
+    let text = &line[level..];
+    if level == 0 || level > 6 || !(text.is_empty() || text.starts_with(' ')) {
+        return None;
+    }
+    Some((level, text.trim()))
+}
+
+/// Whether a heading is about `version`, like `1.0.0`, `v1.0.0` or
+/// `[1.0.0] - 2026-10-18`.
+fn names_version(text: &str, version: &Version) -> bool {
+    text.split(|c: char| c.is_whitespace() || matches!(c, '[' | ']' | '(' | ')' | ','))
+        .map(|word| word.trim_start_matches(['v', 'V']))
+        .any(|word| Version::parse(word).is_ok_and(|v| &v == version))
+}
+
+/// The section of a changelog under the heading naming `version`, up to the
+/// next heading of the same or a higher level. Returns `None` if there is no
+/// such section or it is empty.
+pub fn release_notes(changelog: &str, version: &Version) -> Option<String> {
+    let mut section: Option<(usize, Vec<&str>)> = None;
+    let mut in_code = false;
+
+    for line in changelog.lines() {
+        if !in_code {
+            if let Some((level, text)) = heading(line) {
+                if let Some((section_level, _)) = &section {
+                    if level <= *section_level {
+                        break;
+                    }
+                } else if names_version(text, version) {
+                    section = Some((level, Vec::new()));
+                    continue;
+                }
+            }
+        }
+
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+        }
+        if let Some((_, lines)) = &mut section {
+            lines.push(line);
+        }
+    }
+
+    let notes = section?.1.join("\n").trim().to_string();
+    if notes.is_empty() {
+        None
+    } else {
+        Some(notes)
+    }
+}
+
+#[test]
+fn test_release_notes() {
+    let changelog = "# Changelog
+
+## [Unreleased]
+
+- Nothing yet
+
+## [1.1.0] - 2026-10-18
+
+### Added
+
+- Export to CSV
+
+```sh
+# not a heading
+```
+
+## [1.0.0] - 2026-09-01
+
+- First release
+";
+
+    assert_eq!(
+        release_notes(changelog, &Version::new(1, 1, 0)).unwrap(),
+        "### Added\n\n- Export to CSV\n\n```sh\n# not a heading\n```"
+    );
+    assert_eq!(
+        release_notes(changelog, &Version::new(1, 0, 0)).unwrap(),
+        "- First release"
+    );
+    assert_eq!(release_notes("## v2.0.0\n", &Version::new(2, 0, 0)), None);
+    assert_eq!(release_notes(changelog, &Version::new(0, 9, 0)), None);
+}
@@ -1,37 +1,180 @@
 use crate::{
-    manifest::{PackageDb, PackageManifestDb},
-    package::Package,
+    auth::{scopes_to_string, Scope, TokenDb},
+    description::{self, escape_html},
+    files::FileEntry,
+    jobs::Job,
+    listing::{Cursor, Sort},
+    manifest::{BotType, PackageDb, PackageManifestDb},
+    package::{Asset, Package},
+    signing::{self, SignatureKind, SigningKeyDb},
 };
 
 use actix_web::web::{Bytes, Data};
-use semver::Version;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::Signature;
+use log::error;
+use semver::{Version, VersionReq};
+use serde::Serialize;
 use serde_json::to_string;
-use sqlx::{sqlite::SqliteQueryResult, Error, SqlitePool};
-use tokio::fs::{remove_file, write};
+use sha2::{Digest as _, Sha256};
+use sqlx::{sqlite::SqliteConnectOptions, Error, FromRow, SqliteConnection, SqlitePool};
+use tokio::fs::{create_dir_all, remove_dir_all, remove_file, rename, try_exists, write};
 
-use std::path::Path;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::Error as IOError,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Builds a query returning rows for [`PackageManifestDb`]. `$rest` follows the
+/// join of versions `v` and packages `p`.
+macro_rules! select_versions {
+    ($rest:literal) => {
+        concat!(
+            r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", v."dependencies", v."platform", v."yanked", v."sha256", v."size", v."signing_key", v."published_at", v."release_notes", (SELECT COALESCE(SUM(s."count"), 0) FROM download_stats s WHERE s."version"=v."id") AS "downloads", (SELECT COALESCE(SUM(s."count"), 0) FROM download_stats s WHERE s."version"=v."id" AND s."day">=date('now', '-30 days')) AS "recent_downloads", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") "#,
+            $rest
+        )
+    };
+}
 
-pub fn validate_data(package: &Package) -> bool {
-    let gh = package.manifest.github.clone();
-    if let Some(g) = gh {
-        if g.len() > 40 || g.split('/').count() != 2 {
-            return false;
+/// Adds the collations the schema relies on to `options`.
+pub fn with_collations(options: SqliteConnectOptions) -> SqliteConnectOptions {
+    options.collation("semver_collation", |a, b| {
+        Version::parse(a).unwrap().cmp(&Version::parse(b).unwrap())
+    })
+}
+
+/// Opens a migrated in-memory database.
+#[cfg(test)]
+pub async fn test_pool() -> SqlitePool {
+    use sqlx::sqlite::SqlitePoolOptions;
+    use std::str::FromStr;
+
+    let options = with_collations(SqliteConnectOptions::from_str("sqlite::memory:").unwrap());
+    // Every connection would get its own in-memory database
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect_with(options)
+        .await
+        .unwrap();
+    sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+
+    pool
+}
+
+/// A package field that does not fit the limits of the registry.
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum Violation {
+    /// The field is longer than `limit` characters.
+    MaxLength {
+        field: &'static str,
+        limit: usize,
+        actual: usize,
+    },
+    /// The field has more than `limit` entries.
+    MaxEntries {
+        field: &'static str,
+        limit: usize,
+        actual: usize,
+    },
+    /// The field does not follow the required format.
+    Format {
+        field: &'static str,
+        expected: &'static str,
+        actual: String,
+    },
+}
+
+fn check_length(violations: &mut Vec<Violation>, field: &'static str, value: &str, limit: usize) {
+    if value.len() > limit {
+        violations.push(Violation::MaxLength {
+            field,
+            limit,
+            actual: value.len(),
+        });
+    }
+}
+
+/// Longest release notes in bytes. They are sent with every update check
+/// that finds a newer version.
+pub const MAX_RELEASE_NOTES: usize = 8000;
+
+/// Checks a parsed package against the database limits. Returns all violations found.
+pub fn validate_data(package: &Package) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let manifest = &package.manifest;
+
+    if let Some(g) = &manifest.github {
+        check_length(&mut violations, "github", g, 40);
+        if g.split('/').count() != 2 {
+            violations.push(Violation::Format {
+                field: "github",
+                expected: "Username/Reponame",
+                actual: g.clone(),
+            });
+        }
+    }
+
+    check_length(&mut violations, "author", &manifest.author, 30);
+    check_length(&mut violations, "name", &manifest.name, 30);
+    check_length(&mut violations, "description", &manifest.description, 100);
+    check_length(&mut violations, "README.md", &package.description, 8000);
+    if let Some(notes) = &package.release_notes {
+        check_length(&mut violations, "release_notes", notes, MAX_RELEASE_NOTES);
+    }
+    check_length(
+        &mut violations,
+        "version",
+        &manifest.version.to_string(),
+        12,
+    );
+    check_length(
+        &mut violations,
+        "bot_version",
+        &manifest.bot_version.to_string(),
+        50,
+    );
+    check_length(
+        &mut violations,
+        "bot_type",
+        &manifest.bot_type.to_string(),
+        15,
+    );
+
+    for (field, entries) in [
+        ("requires", &manifest.requires),
+        ("dependencies", &manifest.dependencies),
+        ("platform", &manifest.platform),
+    ] {
+        if entries.len() >= 100 {
+            violations.push(Violation::MaxEntries {
+                field,
+                limit: 99,
+                actual: entries.len(),
+            });
         }
     }
 
-    package.manifest.author.len() <= 30
-        && package.manifest.name.len() <= 30
-        && package.manifest.description.len() <= 100
-        && package.description.len() <= 8000
-        && package.manifest.version.to_string().len() <= 12
-        && package.manifest.bot_version.to_string().len() <= 50
-        && package.manifest.bot_type.to_string().len() <= 15
-        && package.manifest.requires.len() < 100
-        && package
-            .manifest
-            .name
-            .chars()
-            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    if !manifest
+        .name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        violations.push(Violation::Format {
+            field: "name",
+            expected: "alphanumeric characters, - or _",
+            actual: manifest.name.clone(),
+        });
+    }
+
+    violations
 }
 
 pub async fn get_package_with_version(
@@ -41,9 +184,12 @@ pub async fn get_package_with_version(
 ) -> Result<PackageManifestDb, Error> {
     let version_str = version.to_string();
 
-    let data: PackageManifestDb = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=?;"#,
-    ).bind(&name).bind(&version_str).fetch_one(&**pool).await?;
+    let data: PackageManifestDb =
+        sqlx::query_as(select_versions!(r#"WHERE p."name"=? AND v."version"=?;"#))
+            .bind(name)
+            .bind(&version_str)
+            .fetch_one(&**pool)
+            .await?;
 
     Ok(data)
 }
@@ -52,9 +198,12 @@ pub async fn get_latest_package(
     pool: Data<SqlitePool>,
     name: &str,
 ) -> Result<PackageManifestDb, Error> {
-    let data: PackageManifestDb = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? ORDER BY v."version" DESC LIMIT 1;"#,
-    ).bind(&name).fetch_one(&**pool).await?;
+    let data: PackageManifestDb = sqlx::query_as(select_versions!(
+        r#"WHERE p."name"=? AND NOT v."yanked" ORDER BY v."version" DESC LIMIT 1;"#
+    ))
+    .bind(name)
+    .fetch_one(&**pool)
+    .await?;
 
     Ok(data)
 }
@@ -62,101 +211,1417 @@ pub async fn get_latest_package(
 pub async fn get_package_versions(
     pool: Data<SqlitePool>,
     name: &str,
+    include_yanked: bool,
 ) -> Result<Vec<PackageManifestDb>, Error> {
-    let data: Vec<PackageManifestDb> = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? ORDER BY v."version" DESC;"#,
-    ).bind(&name).fetch_all(&**pool).await?;
+    let data: Vec<PackageManifestDb> = sqlx::query_as(select_versions!(
+        r#"WHERE p."name"=? AND (? OR NOT v."yanked") ORDER BY v."version" DESC;"#
+    ))
+    .bind(name)
+    .bind(include_yanked)
+    .fetch_all(&**pool)
+    .await?;
 
     Ok(data)
 }
 
 pub async fn get_all_packages(pool: Data<SqlitePool>) -> Result<Vec<PackageManifestDb>, Error> {
-    let data: Vec<PackageManifestDb> = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") ORDER BY v."package", v."version" DESC;"#,
-    ).fetch_all(&**pool).await?;
+    let data: Vec<PackageManifestDb> = sqlx::query_as(select_versions!(
+        r#"WHERE NOT v."yanked" ORDER BY v."package", v."version" DESC;"#
+    ))
+    .fetch_all(&**pool)
+    .await?;
 
     Ok(data)
 }
 
+/// The newest non-yanked version of each package and bot type.
 pub async fn get_latest_packages(pool: Data<SqlitePool>) -> Result<Vec<PackageManifestDb>, Error> {
-    let data: Vec<PackageManifestDb> = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") GROUP BY v."package", v."bot_type" HAVING MAX(v."version");"#,
-    ).fetch_all(&**pool).await?;
+    get_compatible_packages(pool, None, None, true).await
+}
+
+/// Filters the non-yanked versions of all packages by the bot they support.
+///
+/// With `latest_only`, only the newest matching version of each package and
+/// bot type is returned. Versions are ordered with the semver collation.
+pub async fn get_compatible_packages(
+    pool: Data<SqlitePool>,
+    bot_type: Option<&BotType>,
+    bot_version: Option<&Version>,
+    latest_only: bool,
+) -> Result<Vec<PackageManifestDb>, Error> {
+    let mut data = get_all_packages(pool).await?;
+
+    data.retain(|v| {
+        bot_type.is_none_or(|t| &v.bot_type == t)
+            && bot_version.is_none_or(|b| v.bot_version.matches(b))
+    });
+    if latest_only {
+        let mut seen = HashSet::new();
+        data.retain(|v| seen.insert((v.name.clone(), v.bot_type.to_string())));
+    }
+
+    Ok(data)
+}
+
+/// Returns up to `limit` non-yanked versions for `bot_type` following `after`
+/// in the order of `sort`.
+async fn get_sorted_packages(
+    pool: &SqlitePool,
+    sort: Sort,
+    after: Option<&Cursor>,
+    bot_type: Option<&BotType>,
+    limit: usize,
+) -> Result<Vec<PackageManifestDb>, Error> {
+    // ?2 is the sort key of the cursor, ties are broken by name and version
+    let query = match sort {
+        Sort::Name => select_versions!(
+            r#"WHERE NOT v."yanked" AND (?1 IS NULL OR v."bot_type"=?1) AND (?2 IS NULL OR p."name">?3 OR (p."name"=?3 AND v."version"<?4)) ORDER BY p."name", v."version" DESC LIMIT ?5;"#
+        ),
+        Sort::Updated => select_versions!(
+            r#"WHERE NOT v."yanked" AND (?1 IS NULL OR v."bot_type"=?1) AND (?2 IS NULL OR COALESCE(v."published_at", '')<?2 OR (COALESCE(v."published_at", '')=?2 AND (p."name">?3 OR (p."name"=?3 AND v."version"<?4)))) ORDER BY COALESCE(v."published_at", '') DESC, p."name", v."version" DESC LIMIT ?5;"#
+        ),
+        Sort::Downloads => select_versions!(
+            r#"WHERE NOT v."yanked" AND (?1 IS NULL OR v."bot_type"=?1) AND (?2 IS NULL OR "downloads"<?2 OR ("downloads"=?2 AND (p."name">?3 OR (p."name"=?3 AND v."version"<?4)))) ORDER BY "downloads" DESC, p."name", v."version" DESC LIMIT ?5;"#
+        ),
+    };
+
+    let query = sqlx::query_as(query).bind(bot_type.map(ToString::to_string));
+    let query = match sort {
+        Sort::Name => query.bind(after.map(|c| c.name.clone())),
+        Sort::Updated => query.bind(after.map(|c| c.published_at.clone().unwrap_or_default())),
+        Sort::Downloads => query.bind(after.map(|c| c.downloads)),
+    };
+
+    let data: Vec<PackageManifestDb> = query
+        .bind(after.map(|c| c.name.clone()))
+        .bind(after.map(|c| c.version.to_string()))
+        .bind(limit as i64)
+        .fetch_all(pool)
+        .await?;
+
+    Ok(data)
+}
+
+/// Returns the non-yanked versions following `after` in the order of `sort`,
+/// filtered like [`get_compatible_packages`] does. Up to `limit + 1` versions
+/// are returned, so callers can tell whether more follow.
+pub async fn get_packages_page(
+    pool: Data<SqlitePool>,
+    sort: Sort,
+    after: Option<&Cursor>,
+    bot_type: Option<&BotType>,
+    bot_version: Option<&Version>,
+    limit: usize,
+) -> Result<Vec<PackageManifestDb>, Error> {
+    let mut data = Vec::new();
+    let mut last: Option<Cursor> = None;
+
+    // Version requirements can only be matched here, so keep fetching until
+    // enough versions match or there are no more
+    loop {
+        let batch =
+            get_sorted_packages(&pool, sort, last.as_ref().or(after), bot_type, limit + 1).await?;
+        let exhausted = batch.len() <= limit;
+        last = batch.last().map(|v| Cursor::at(sort, v));
+
+        data.extend(
+            batch
+                .into_iter()
+                .filter(|v| bot_version.is_none_or(|b| v.bot_version.matches(b))),
+        );
+        if exhausted || data.len() > limit {
+            break;
+        }
+    }
+
+    data.truncate(limit + 1);
+    Ok(data)
+}
+
+pub async fn get_package(pool: Data<SqlitePool>, name: &str) -> Result<Option<PackageDb>, Error> {
+    let data: Option<PackageDb> =
+        sqlx::query_as(r#"SELECT "id", "owner" FROM packages WHERE "name"=?;"#)
+            .bind(name)
+            .fetch_optional(&**pool)
+            .await?;
+
+    Ok(data)
+}
+
+/// Marks a version as yanked or not yanked. Returns whether the version exists.
+pub async fn set_yanked(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    version: &Version,
+    yanked: bool,
+) -> Result<bool, Error> {
+    let res = sqlx::query(r#"UPDATE versions SET "yanked"=? WHERE "package"=? AND "version"=?;"#)
+        .bind(yanked)
+        .bind(package_id)
+        .bind(version.to_string())
+        .execute(&**pool)
+        .await?;
+
+    Ok(res.rows_affected() != 0)
+}
+
+/// Replaces the release notes of a version. Returns `false` if there is no such version.
+pub async fn set_release_notes(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    version: &Version,
+    notes: &str,
+) -> Result<bool, Error> {
+    let res =
+        sqlx::query(r#"UPDATE versions SET "release_notes"=? WHERE "package"=? AND "version"=?;"#)
+            .bind(notes)
+            .bind(package_id)
+            .bind(version.to_string())
+            .execute(&**pool)
+            .await?;
+
+    Ok(res.rows_affected() != 0)
+}
+
+/// Sets or, with `None`, removes the secret GitHub webhooks for a package are signed with.
+pub async fn set_webhook_secret(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    secret: Option<&str>,
+) -> Result<(), Error> {
+    sqlx::query(r#"UPDATE packages SET "webhook_secret"=? WHERE "id"=?;"#)
+        .bind(secret)
+        .bind(package_id)
+        .execute(&**pool)
+        .await?;
+
+    Ok(())
+}
+
+/// A package a webhook delivery can be for.
+#[derive(FromRow)]
+pub struct WebhookPackage {
+    pub id: i64,
+    pub name: String,
+    /// The secret its webhooks are signed with, `None` for the registry-wide one.
+    pub webhook_secret: Option<String>,
+}
+
+/// The packages of `owner` whose latest version is published from the GitHub
+/// repository `github`.
+pub async fn get_webhook_packages(
+    pool: Data<SqlitePool>,
+    github: &str,
+    owner: i64,
+) -> Result<Vec<WebhookPackage>, Error> {
+    let data: Vec<WebhookPackage> = sqlx::query_as(
+        r#"SELECT p."id", p."name", p."webhook_secret" FROM packages p WHERE p."owner"=? AND (SELECT v."github" FROM versions v WHERE v."package"=p."id" ORDER BY v."id" DESC LIMIT 1)=? ORDER BY p."name";"#,
+    )
+    .bind(owner)
+    .bind(github)
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+/// Whether a webhook delivery ID was handled before.
+pub async fn is_delivery_recorded(pool: Data<SqlitePool>, delivery: &str) -> Result<bool, Error> {
+    let data: Option<(String,)> =
+        sqlx::query_as(r#"SELECT "delivery" FROM webhook_deliveries WHERE "delivery"=?;"#)
+            .bind(delivery)
+            .fetch_optional(&**pool)
+            .await?;
+
+    Ok(data.is_some())
+}
+
+/// Remembers the ID of a handled webhook delivery and forgets those older
+/// than the delivery log.
+pub async fn record_delivery(pool: Data<SqlitePool>, delivery: &str) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"INSERT OR IGNORE INTO webhook_deliveries ("delivery") VALUES (?);"#)
+        .bind(delivery)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(r#"DELETE FROM webhook_deliveries WHERE "received_at"<datetime('now', ?);"#)
+        .bind(format!("-{} days", DELIVERY_LOG_DAYS))
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// Days webhook deliveries are kept in the delivery log.
+const DELIVERY_LOG_DAYS: i64 = 30;
+
+/// A webhook delivery as received from GitHub.
+#[derive(FromRow)]
+pub struct WebhookDelivery {
+    /// The ID of the package the delivery was handled for.
+    pub package: i64,
+    /// The GitHub delivery ID.
+    pub delivery: Option<String>,
+    pub event: Option<String>,
+    pub repository: String,
+    pub action: Option<String>,
+    /// The payload, only kept for verified deliveries so they can be redelivered.
+    pub payload: Option<String>,
+}
+
+/// Adds a delivery handled for a package to the delivery log and drops
+/// entries that expired. `status` is the HTTP status of the response,
+/// `outcome` what was done or, if the delivery was rejected, the error code.
+pub async fn log_delivery(
+    pool: Data<SqlitePool>,
+    delivery: &WebhookDelivery,
+    status: u16,
+    outcome: &str,
+    error: Option<&str>,
+    job: Option<i64>,
+    redelivery: bool,
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"INSERT INTO delivery_log ("package", "delivery", "event", "repository", "action", "status", "outcome", "error", "job", "payload", "redelivery") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?);"#,
+    )
+    .bind(delivery.package)
+    .bind(&delivery.delivery)
+    .bind(&delivery.event)
+    .bind(&delivery.repository)
+    .bind(&delivery.action)
+    .bind(status)
+    .bind(outcome)
+    .bind(error)
+    .bind(job)
+    .bind(&delivery.payload)
+    .bind(redelivery)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(r#"DELETE FROM delivery_log WHERE "received_at"<datetime('now', ?);"#)
+        .bind(format!("-{} days", DELIVERY_LOG_DAYS))
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await
+}
+
+/// A logged webhook delivery as shown to the package owner.
+#[derive(Serialize, FromRow)]
+pub struct Delivery {
+    pub id: i64,
+    /// The GitHub delivery ID.
+    pub delivery: Option<String>,
+    pub event: Option<String>,
+    pub repository: String,
+    pub action: Option<String>,
+    pub status: i64,
+    pub outcome: String,
+    pub error: Option<String>,
+    pub job: Option<i64>,
+    /// Whether the payload was kept, which it is for verified deliveries.
+    pub redeliverable: bool,
+    pub redelivery: bool,
+    pub received_at: String,
+}
+
+/// Most deliveries returned by [`get_deliveries`].
+const MAX_DELIVERIES: i64 = 50;
+
+/// The latest deliveries handled for a package, newest first.
+pub async fn get_deliveries(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+) -> Result<Vec<Delivery>, Error> {
+    let data: Vec<Delivery> = sqlx::query_as(
+        r#"SELECT "id", "delivery", "event", "repository", "action", "status", "outcome", "error", "job", "payload" IS NOT NULL AS "redeliverable", "redelivery", "received_at" FROM delivery_log WHERE "package"=? ORDER BY "id" DESC LIMIT ?;"#,
+    )
+    .bind(package_id)
+    .bind(MAX_DELIVERIES)
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+/// Looks up a logged delivery that was handled for a package.
+pub async fn get_delivery(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    id: i64,
+) -> Result<Option<WebhookDelivery>, Error> {
+    let data: Option<WebhookDelivery> = sqlx::query_as(
+        r#"SELECT "package", "delivery", "event", "repository", "action", "payload" FROM delivery_log WHERE "id"=? AND "package"=?;"#,
+    )
+    .bind(id)
+    .bind(package_id)
+    .fetch_optional(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+/// Queues the import of a GitHub release. Returns the ID of the job.
+pub async fn enqueue_job(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    delivery: Option<&str>,
+    repository: &str,
+    tag: &str,
+    release: &str,
+) -> Result<i64, Error> {
+    let id = sqlx::query(
+        r#"INSERT INTO jobs ("package", "delivery", "repository", "tag", "release") VALUES (?, ?, ?, ?, ?);"#,
+    )
+    .bind(package_id)
+    .bind(delivery)
+    .bind(repository)
+    .bind(tag)
+    .bind(release)
+    .execute(&**pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+/// A job taken from the queue by the worker.
+#[derive(FromRow)]
+pub struct QueuedJob {
+    pub id: i64,
+    /// The name of the package the release is imported into.
+    pub package: String,
+    /// The current owner of the package.
+    pub owner: i64,
+    /// The GitHub repository the release belongs to.
+    pub repository: String,
+    pub tag: String,
+    /// The release as JSON.
+    pub release: String,
+    /// Attempts including the current one.
+    pub attempts: i64,
+}
+
+/// Marks the queued job that is due first as running and returns it.
+pub async fn claim_job(pool: Data<SqlitePool>) -> Result<Option<QueuedJob>, Error> {
+    let mut tx = pool.begin().await?;
+
+    let id: Option<(i64,)> = sqlx::query_as(
+        r#"UPDATE jobs SET "status"='running', "attempts"="attempts"+1, "updated_at"=CURRENT_TIMESTAMP WHERE "id"=(SELECT "id" FROM jobs WHERE "status"='queued' AND "run_at"<=CURRENT_TIMESTAMP ORDER BY "run_at", "id" LIMIT 1) RETURNING "id";"#,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let data: Option<QueuedJob> = match id {
+        Some((id,)) => Some(
+            sqlx::query_as(
+                r#"SELECT j."id", p."name" AS "package", p."owner", j."repository", j."tag", j."release", j."attempts" FROM jobs j JOIN packages p ON (j."package"=p."id") WHERE j."id"=?;"#,
+            )
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?,
+        ),
+        None => None,
+    };
+
+    tx.commit().await?;
+
+    Ok(data)
+}
+
+pub async fn finish_job(pool: Data<SqlitePool>, id: i64) -> Result<(), Error> {
+    sqlx::query(
+        r#"UPDATE jobs SET "status"='succeeded', "last_error"=NULL, "updated_at"=CURRENT_TIMESTAMP WHERE "id"=?;"#,
+    )
+    .bind(id)
+    .execute(&**pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Queues a failed job again to run after `delay`.
+pub async fn reschedule_job(
+    pool: Data<SqlitePool>,
+    id: i64,
+    error: &str,
+    delay: Duration,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"UPDATE jobs SET "status"='queued', "last_error"=?, "run_at"=datetime('now', ?), "updated_at"=CURRENT_TIMESTAMP WHERE "id"=?;"#,
+    )
+    .bind(error)
+    .bind(format!("+{} seconds", delay.as_secs()))
+    .bind(id)
+    .execute(&**pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Moves a failed job to the dead-letter state, where it waits for the owner.
+pub async fn bury_job(pool: Data<SqlitePool>, id: i64, error: &str) -> Result<(), Error> {
+    sqlx::query(
+        r#"UPDATE jobs SET "status"='dead', "last_error"=?, "updated_at"=CURRENT_TIMESTAMP WHERE "id"=?;"#,
+    )
+    .bind(error)
+    .bind(id)
+    .execute(&**pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Queues the jobs that were running when the server stopped again.
+pub async fn requeue_running_jobs(pool: &SqlitePool) -> Result<(), Error> {
+    sqlx::query(
+        r#"UPDATE jobs SET "status"='queued', "updated_at"=CURRENT_TIMESTAMP WHERE "status"='running';"#,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Most jobs returned by [`get_jobs`].
+const MAX_JOBS: i64 = 50;
+
+/// The latest jobs of a package, newest first.
+pub async fn get_jobs(pool: Data<SqlitePool>, package_id: i64) -> Result<Vec<Job>, Error> {
+    let data: Vec<Job> = sqlx::query_as(
+        r#"SELECT "id", "tag", "delivery", "status", "attempts", "last_error", "run_at", "created_at", "updated_at" FROM jobs WHERE "package"=? ORDER BY "id" DESC LIMIT ?;"#,
+    )
+    .bind(package_id)
+    .bind(MAX_JOBS)
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+pub async fn get_job(
+    pool: Data<SqlitePool>,
+    package_id: i64,
+    id: i64,
+) -> Result<Option<Job>, Error> {
+    let data: Option<Job> = sqlx::query_as(
+        r#"SELECT "id", "tag", "delivery", "status", "attempts", "last_error", "run_at", "created_at", "updated_at" FROM jobs WHERE "package"=? AND "id"=?;"#,
+    )
+    .bind(package_id)
+    .bind(id)
+    .fetch_optional(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+/// Queues a dead or waiting job to run right away, with all attempts left.
+/// Returns `false` if the job cannot be retried.
+pub async fn retry_job(pool: Data<SqlitePool>, package_id: i64, id: i64) -> Result<bool, Error> {
+    let res = sqlx::query(
+        r#"UPDATE jobs SET "status"='queued', "attempts"=0, "run_at"=CURRENT_TIMESTAMP, "updated_at"=CURRENT_TIMESTAMP WHERE "package"=? AND "id"=? AND ("status"='dead' OR ("status"='queued' AND "attempts">0));"#,
+    )
+    .bind(package_id)
+    .bind(id)
+    .execute(&**pool)
+    .await?;
+
+    Ok(res.rows_affected() != 0)
+}
+
+/// Most results returned by [`search`].
+const MAX_SEARCH_RESULTS: i64 = 50;
+
+#[derive(Serialize, FromRow)]
+pub struct SearchResult {
+    pub name: String,
+    pub version: String,
+    pub short_description: String,
+    pub author: String,
+    /// HTML excerpt of the best matching field, matches wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+/// Turns user input into an FTS5 query matching all words as prefixes.
+fn search_query(input: &str) -> Option<String> {
+    let terms: Vec<String> = input
+        .split_whitespace()
+        .take(10)
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Searches the latest non-yanked version of every package, best matches first.
+///
+/// Matches in the name rank highest, followed by the short description, the
+/// author and the README text.
+pub async fn search(pool: Data<SqlitePool>, input: &str) -> Result<Vec<SearchResult>, Error> {
+    let query = match search_query(input) {
+        Some(query) => query,
+        None => return Ok(Vec::new()),
+    };
+
+    let mut data: Vec<SearchResult> = sqlx::query_as(
+        r#"SELECT p."name", v."version", v."short_description", v."author", snippet(package_search, -1, char(2), char(3), '…', 16) AS "snippet" FROM package_search s JOIN versions v ON (s.rowid=v."id") JOIN packages p ON (v."package"=p."id") WHERE package_search MATCH ? AND NOT v."yanked" AND v."version"=(SELECT MAX(w."version") FROM versions w WHERE w."package"=v."package" AND NOT w."yanked") ORDER BY bm25(package_search, 10.0, 5.0, 3.0, 1.0) LIMIT ?;"#,
+    ).bind(query).bind(MAX_SEARCH_RESULTS).fetch_all(&**pool).await?;
+
+    for result in &mut data {
+        result.snippet = escape_html(&result.snippet)
+            .replace('\u{2}', "<mark>")
+            .replace('\u{3}', "</mark>");
+    }
+
+    Ok(data)
+}
+
+/// Rendered description of a stored version, for rebuilding derived columns.
+#[derive(FromRow)]
+pub struct StoredDescription {
+    pub id: i64,
+    pub name: String,
+    pub version: String,
+    pub description: String,
+}
+
+pub async fn get_all_descriptions(pool: &SqlitePool) -> Result<Vec<StoredDescription>, Error> {
+    let data: Vec<StoredDescription> = sqlx::query_as(
+        r#"SELECT v."id", p."name", v."version", v."description" FROM versions v JOIN packages p ON (v."package"=p."id") ORDER BY v."id";"#,
+    ).fetch_all(pool).await?;
+
+    Ok(data)
+}
+
+/// Rendered descriptions of the versions whose README text is not in the search index.
+pub async fn get_unindexed_descriptions(
+    pool: &SqlitePool,
+) -> Result<Vec<StoredDescription>, Error> {
+    let data: Vec<StoredDescription> = sqlx::query_as(
+        r#"SELECT v."id", p."name", v."version", v."description" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE v."readme_text" IS NULL ORDER BY v."id";"#,
+    ).fetch_all(pool).await?;
+
+    Ok(data)
+}
+
+/// Replaces the rendered description of a version and its text in the search index.
+pub async fn set_description(
+    pool: &SqlitePool,
+    version_id: i64,
+    description: &str,
+) -> Result<(), Error> {
+    sqlx::query(r#"UPDATE versions SET "description"=?, "readme_text"=? WHERE "id"=?;"#)
+        .bind(description)
+        .bind(description::to_text(description))
+        .bind(version_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Updates the text of a version's README in the search index.
+pub async fn set_readme_text(pool: &SqlitePool, version_id: i64, text: &str) -> Result<(), Error> {
+    sqlx::query(r#"UPDATE versions SET "readme_text"=? WHERE "id"=?;"#)
+        .bind(text)
+        .bind(version_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// A non-yanked version of another package that depends on a package.
+#[derive(Serialize, FromRow)]
+pub struct Dependent {
+    pub name: String,
+    pub version: String,
+    pub requirement: String,
+}
+
+/// Looks up the reverse dependencies of a package, newest versions first.
+pub async fn get_dependents(pool: Data<SqlitePool>, name: &str) -> Result<Vec<Dependent>, Error> {
+    let data: Vec<Dependent> = sqlx::query_as(
+        r#"SELECT p."name", v."version", d."requirement" FROM version_dependencies d JOIN versions v ON (d."version"=v."id") JOIN packages p ON (v."package"=p."id") WHERE d."dependency"=? AND NOT v."yanked" ORDER BY p."name", v."version" DESC;"#,
+    ).bind(name).fetch_all(&**pool).await?;
+
+    Ok(data)
+}
+
+pub async fn create_token(
+    pool: Data<SqlitePool>,
+    owner: i64,
+    name: &str,
+    hash: &str,
+    scopes: &[Scope],
+) -> Result<i64, Error> {
+    let id = sqlx::query(
+        r#"INSERT INTO tokens ("owner", "name", "hash", "scopes") VALUES (?, ?, ?, ?);"#,
+    )
+    .bind(owner)
+    .bind(name)
+    .bind(hash)
+    .bind(scopes_to_string(scopes))
+    .execute(&**pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn get_tokens(pool: Data<SqlitePool>, owner: i64) -> Result<Vec<TokenDb>, Error> {
+    let data: Vec<TokenDb> = sqlx::query_as(
+        r#"SELECT "id", "owner", "name", "scopes", "created_at", "last_used_at" FROM tokens WHERE "owner"=? ORDER BY "id" DESC;"#,
+    ).bind(owner).fetch_all(&**pool).await?;
+
+    Ok(data)
+}
+
+pub async fn get_token_by_hash(
+    pool: Data<SqlitePool>,
+    hash: &str,
+) -> Result<Option<TokenDb>, Error> {
+    let data: Option<TokenDb> = sqlx::query_as(
+        r#"SELECT "id", "owner", "name", "scopes", "created_at", "last_used_at" FROM tokens WHERE "hash"=?;"#,
+    ).bind(hash).fetch_optional(&**pool).await?;
 
     Ok(data)
 }
 
-pub async fn get_package_by_repo(
+pub async fn touch_token(pool: Data<SqlitePool>, id: i64) -> Result<(), Error> {
+    sqlx::query(r#"UPDATE tokens SET "last_used_at"=CURRENT_TIMESTAMP WHERE "id"=?;"#)
+        .bind(id)
+        .execute(&**pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Deletes a token of the given owner. Returns whether it existed.
+pub async fn revoke_token(pool: Data<SqlitePool>, owner: i64, id: i64) -> Result<bool, Error> {
+    let res = sqlx::query(r#"DELETE FROM tokens WHERE "id"=? AND "owner"=?;"#)
+        .bind(id)
+        .bind(owner)
+        .execute(&**pool)
+        .await?;
+
+    Ok(res.rows_affected() != 0)
+}
+
+pub async fn create_signing_key(
     pool: Data<SqlitePool>,
-    github: String,
     owner: i64,
-) -> Result<Option<PackageManifestDb>, Error> {
-    let data: Option<PackageManifestDb> = sqlx::query_as(
-        r#"SELECT v."description", v."short_description", v."author", v."version", v."bot_version", v."bot_type", p."name", v."github", v."requires", p."owner" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE v."github"=? AND p."owner"=? ORDER BY v."id" DESC LIMIT 1;"#,
-    ).bind(github).bind(owner).fetch_optional(&**pool).await?;
+    name: &str,
+    public_key: &str,
+) -> Result<i64, Error> {
+    let id = sqlx::query(
+        r#"INSERT INTO signing_keys ("owner", "name", "public_key") VALUES (?, ?, ?);"#,
+    )
+    .bind(owner)
+    .bind(name)
+    .bind(public_key)
+    .execute(&**pool)
+    .await?
+    .last_insert_rowid();
+
+    Ok(id)
+}
+
+pub async fn get_signing_keys(
+    pool: Data<SqlitePool>,
+    owner: i64,
+) -> Result<Vec<SigningKeyDb>, Error> {
+    let data: Vec<SigningKeyDb> = sqlx::query_as(
+        r#"SELECT "id", "owner", "name", "public_key", "created_at", "revoked_at" FROM signing_keys WHERE "owner"=? ORDER BY "id" DESC;"#,
+    ).bind(owner).fetch_all(&**pool).await?;
+
+    Ok(data)
+}
+
+/// Revokes a signing key of the given owner. Versions signed with it keep
+/// referencing it. Returns whether an active key was revoked.
+pub async fn revoke_signing_key(
+    pool: Data<SqlitePool>,
+    owner: i64,
+    id: i64,
+) -> Result<bool, Error> {
+    let res = sqlx::query(
+        r#"UPDATE signing_keys SET "revoked_at"=CURRENT_TIMESTAMP WHERE "id"=? AND "owner"=? AND "revoked_at" IS NULL;"#,
+    )
+    .bind(id)
+    .bind(owner)
+    .execute(&**pool)
+    .await?;
+
+    Ok(res.rows_affected() != 0)
+}
+
+#[derive(Serialize)]
+pub struct VersionSignature {
+    pub kind: String,
+    pub signature: String,
+    pub key: SigningKeyDb,
+}
+
+pub async fn get_signature(
+    pool: Data<SqlitePool>,
+    name: &str,
+    version: &Version,
+) -> Result<Option<VersionSignature>, Error> {
+    let row: Option<(String, String, i64)> = sqlx::query_as(
+        r#"SELECT v."signature_kind", v."signature", v."signing_key" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=? AND v."signature" IS NOT NULL;"#,
+    ).bind(name).bind(version.to_string()).fetch_optional(&**pool).await?;
+
+    let (kind, signature, key_id) = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+
+    let key: SigningKeyDb = sqlx::query_as(
+        r#"SELECT "id", "owner", "name", "public_key", "created_at", "revoked_at" FROM signing_keys WHERE "id"=?;"#,
+    ).bind(key_id).fetch_one(&**pool).await?;
+
+    Ok(Some(VersionSignature {
+        kind,
+        signature,
+        key,
+    }))
+}
+
+/// Counts a served download of a version for the current day.
+pub async fn record_download(
+    pool: Data<SqlitePool>,
+    name: &str,
+    version: &Version,
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"INSERT INTO download_stats ("version", "day", "count") SELECT v."id", date('now'), 1 FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=? ON CONFLICT ("version", "day") DO UPDATE SET "count"="count"+1;"#,
+    )
+    .bind(name)
+    .bind(version.to_string())
+    .execute(&**pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Downloads of one version of a package on one day.
+#[derive(FromRow)]
+pub struct DailyDownloads {
+    pub day: String,
+    pub version: String,
+    pub count: i64,
+}
+
+/// Looks up the downloads of all versions of a package in the last `days`
+/// days, oldest first.
+pub async fn get_daily_downloads(
+    pool: Data<SqlitePool>,
+    name: &str,
+    days: u32,
+) -> Result<Vec<DailyDownloads>, Error> {
+    let data: Vec<DailyDownloads> = sqlx::query_as(
+        r#"SELECT s."day", v."version", s."count" FROM download_stats s JOIN versions v ON (s."version"=v."id") JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND s."day">=date('now', ?) ORDER BY s."day", v."version";"#,
+    ).bind(name).bind(format!("-{} days", days)).fetch_all(&**pool).await?;
+
+    Ok(data)
+}
+
+/// A README asset of a version, as served to browsers.
+#[derive(FromRow)]
+pub struct StoredAsset {
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+pub async fn get_asset(
+    pool: Data<SqlitePool>,
+    name: &str,
+    version: &Version,
+    path: &str,
+) -> Result<Option<StoredAsset>, Error> {
+    let data: Option<StoredAsset> = sqlx::query_as(
+        r#"SELECT a."content_type", a."data" FROM version_assets a JOIN versions v ON (a."version"=v."id") JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=? AND a."path"=?;"#,
+    ).bind(name).bind(version.to_string()).bind(path).fetch_optional(&**pool).await?;
 
     Ok(data)
 }
 
+async fn insert_assets(
+    conn: &mut SqliteConnection,
+    version_id: i64,
+    assets: &[Asset],
+) -> Result<(), Error> {
+    for asset in assets {
+        sqlx::query(
+            r#"INSERT INTO version_assets ("version", "path", "content_type", "data") VALUES (?, ?, ?, ?);"#,
+        )
+        .bind(version_id)
+        .bind(&asset.path)
+        .bind(asset.content_type)
+        .bind(&asset.data)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the README assets of a version.
+pub async fn set_assets(pool: &SqlitePool, version_id: i64, assets: &[Asset]) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"DELETE FROM version_assets WHERE "version"=?;"#)
+        .bind(version_id)
+        .execute(&mut *tx)
+        .await?;
+    insert_assets(&mut tx, version_id, assets).await?;
+
+    tx.commit().await
+}
+
+/// The files in the archive of a version, sorted by path.
+pub async fn get_files(
+    pool: Data<SqlitePool>,
+    name: &str,
+    version: &Version,
+) -> Result<Vec<FileEntry>, Error> {
+    let data: Vec<FileEntry> = sqlx::query_as(
+        r#"SELECT f."path", f."size", f."sha256" FROM version_files f JOIN versions v ON (f."version"=v."id") JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=? ORDER BY f."path";"#,
+    )
+    .bind(name)
+    .bind(version.to_string())
+    .fetch_all(&**pool)
+    .await?;
+
+    Ok(data)
+}
+
+async fn insert_files(
+    tx: &mut SqliteConnection,
+    version_id: i64,
+    files: &[FileEntry],
+) -> Result<(), Error> {
+    for file in files {
+        sqlx::query(
+            r#"INSERT INTO version_files ("version", "path", "size", "sha256") VALUES (?, ?, ?, ?);"#,
+        )
+        .bind(version_id)
+        .bind(&file.path)
+        .bind(file.size as i64)
+        .bind(&file.sha256)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the file listing of a version.
+pub async fn set_files(
+    pool: &SqlitePool,
+    version_id: i64,
+    files: &[FileEntry],
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(r#"DELETE FROM version_files WHERE "version"=?;"#)
+        .bind(version_id)
+        .execute(&mut *tx)
+        .await?;
+    insert_files(&mut tx, version_id, files).await?;
+
+    tx.commit().await
+}
+
+/// Checksum and size of a stored package artifact.
+#[derive(FromRow)]
+pub struct Digest {
+    pub sha256: String,
+    pub size: i64,
+}
+
+pub async fn get_digest(
+    pool: Data<SqlitePool>,
+    name: &str,
+    version: &Version,
+) -> Result<Option<Digest>, Error> {
+    let data: Option<Digest> = sqlx::query_as(
+        r#"SELECT v."sha256", v."size" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND v."version"=? AND v."sha256" IS NOT NULL;"#,
+    ).bind(name).bind(version.to_string()).fetch_optional(&**pool).await?;
+
+    Ok(data)
+}
+
+#[derive(FromRow)]
+pub struct StoredArtifact {
+    pub id: i64,
+    pub name: String,
+    pub version: String,
+    pub sha256: Option<String>,
+    pub size: Option<i64>,
+}
+
+pub async fn get_all_artifacts(pool: &SqlitePool) -> Result<Vec<StoredArtifact>, Error> {
+    let data: Vec<StoredArtifact> = sqlx::query_as(
+        r#"SELECT v."id", p."name", v."version", v."sha256", v."size" FROM versions v JOIN packages p ON (v."package"=p."id") ORDER BY p."name", v."version";"#,
+    ).fetch_all(pool).await?;
+
+    Ok(data)
+}
+
+/// Artifacts of versions whose files are not listed yet.
+pub async fn get_unlisted_artifacts(pool: &SqlitePool) -> Result<Vec<StoredArtifact>, Error> {
+    let data: Vec<StoredArtifact> = sqlx::query_as(
+        r#"SELECT v."id", p."name", v."version", v."sha256", v."size" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE NOT EXISTS (SELECT 1 FROM version_files f WHERE f."version"=v."id") ORDER BY v."id";"#,
+    ).fetch_all(pool).await?;
+
+    Ok(data)
+}
+
+pub async fn set_digest(pool: &SqlitePool, version_id: i64, digest: &Digest) -> Result<(), Error> {
+    sqlx::query(r#"UPDATE versions SET "sha256"=?, "size"=? WHERE "id"=?;"#)
+        .bind(&digest.sha256)
+        .bind(digest.size)
+        .bind(version_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Error raised while publishing a package version.
+#[derive(Debug)]
+pub enum PublishError {
+    /// The package exists and belongs to somebody else.
+    Forbidden,
+    /// The version was published before. Versions are immutable.
+    VersionExists,
+    /// None of the owner's signing keys verifies the signature.
+    InvalidSignature,
+    /// Dependencies that no published version satisfies.
+    MissingDependencies(Vec<String>),
+    /// An artifact of the version is stored without being published.
+    ArtifactExists,
+    Database(Error),
+    Io(IOError),
+}
+
+impl Display for PublishError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Forbidden => write!(f, "package is owned by somebody else"),
+            Self::VersionExists => write!(f, "version has already been published"),
+            Self::InvalidSignature => write!(
+                f,
+                "signature does not match any of your registered signing keys"
+            ),
+            Self::MissingDependencies(deps) => write!(
+                f,
+                "no published version satisfies the dependencies {}",
+                deps.join(", ")
+            ),
+            Self::ArtifactExists => write!(f, "an unpublished artifact of this version is stored"),
+            Self::Database(e) => write!(f, "database error: {}", e),
+            Self::Io(e) => write!(f, "could not store artifact: {}", e),
+        }
+    }
+}
+
+impl From<Error> for PublishError {
+    fn from(e: Error) -> Self {
+        Self::Database(e)
+    }
+}
+
+impl From<IOError> for PublishError {
+    fn from(e: IOError) -> Self {
+        Self::Io(e)
+    }
+}
+
+static STAGING_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+pub fn artifact_path(name: &str, version: &Version) -> PathBuf {
+    Path::new("data").join(format!("{}-{}.zip", name, version))
+}
+
+fn staging_path(name: &str, version: &Version) -> PathBuf {
+    let n = STAGING_COUNTER.fetch_add(1, Ordering::Relaxed);
+    Path::new("data").join(".staging").join(format!(
+        "{}-{}-{}-{}.zip",
+        name,
+        version,
+        process::id(),
+        n
+    ))
+}
+
+/// Removes uploads that were staged but never committed, e.g. because the
+/// process died halfway through a publish.
+pub async fn clean_staging() -> Result<(), IOError> {
+    let dir = Path::new("data").join(".staging");
+    if dir.exists() {
+        remove_dir_all(&dir).await?;
+    }
+    create_dir_all(&dir).await
+}
+
+/// Checks whether `owner_id` may publish `version` of the package `name`.
+///
+/// Returns the ID of the package if it exists already, or `None` if the name is
+/// still available. Without an owner, only the version is checked.
+async fn check_publish(
+    conn: &mut SqliteConnection,
+    name: &str,
+    version: &str,
+    owner_id: Option<i64>,
+) -> Result<Option<i64>, PublishError> {
+    let pkg: Option<PackageDb> =
+        sqlx::query_as(r#"SELECT "id", "owner" FROM packages WHERE "name"=?;"#)
+            .bind(name)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+    let p = match pkg {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+
+    if owner_id.is_some_and(|id| id != p.owner) {
+        return Err(PublishError::Forbidden);
+    }
+
+    let existing: Option<(i64,)> =
+        sqlx::query_as(r#"SELECT "id" FROM versions WHERE "package"=? AND "version"=?;"#)
+            .bind(p.id)
+            .bind(version)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+    if existing.is_some() {
+        return Err(PublishError::VersionExists);
+    }
+
+    Ok(Some(p.id))
+}
+
+/// Package dependencies and platform requirements of a version.
+pub struct Requirements {
+    pub dependencies: HashMap<String, VersionReq>,
+    pub platform: HashMap<String, VersionReq>,
+}
+
+/// Sorts the requirements of a package into dependencies and platform requirements.
+///
+/// Entries of the legacy `requires` section count as dependencies if they name
+/// a registered package. Every dependency needs a published, non-yanked
+/// version matching it.
+async fn check_requirements(
+    conn: &mut SqliteConnection,
+    package: &Package,
+) -> Result<Requirements, PublishError> {
+    let manifest = &package.manifest;
+    let mut dependencies = manifest.dependencies.clone();
+    let mut platform = manifest.platform.clone();
+
+    for (name, req) in &manifest.requires {
+        let exists: Option<(i64,)> = sqlx::query_as(r#"SELECT "id" FROM packages WHERE "name"=?;"#)
+            .bind(name)
+            .fetch_optional(&mut *conn)
+            .await?;
+
+        if exists.is_some() {
+            dependencies.insert(name.clone(), req.clone());
+        } else {
+            platform.insert(name.clone(), req.clone());
+        }
+    }
+
+    let mut missing = Vec::new();
+    for (name, req) in &dependencies {
+        let versions: Vec<(String,)> = sqlx::query_as(
+            r#"SELECT v."version" FROM versions v JOIN packages p ON (v."package"=p."id") WHERE p."name"=? AND NOT v."yanked";"#,
+        )
+        .bind(name)
+        .fetch_all(&mut *conn)
+        .await?;
+
+        if !versions
+            .iter()
+            .any(|(v,)| req.matches(&Version::parse(v).unwrap()))
+        {
+            missing.push(format!("{} {}", name, req));
+        }
+    }
+
+    if !missing.is_empty() {
+        missing.sort();
+        return Err(PublishError::MissingDependencies(missing));
+    }
+
+    Ok(Requirements {
+        dependencies,
+        platform,
+    })
+}
+
+/// A verified signature of a package version.
+pub struct SignatureRecord {
+    pub kind: SignatureKind,
+    pub signature: String,
+    pub key_id: i64,
+}
+
+/// Finds the active signing key of `owner_id` that signed the package.
+///
+/// A detached signature signs `file`, a signature in the archive signs its
+/// content listing. Returns `None` for unsigned packages.
+async fn verify_signature(
+    conn: &mut SqliteConnection,
+    owner_id: i64,
+    package: &Package,
+    file: &[u8],
+    detached: Option<&Signature>,
+) -> Result<Option<SignatureRecord>, PublishError> {
+    let (kind, signature, message) = match (detached, &package.archive_signature) {
+        (Some(signature), _) => (SignatureKind::Detached, signature, file),
+        (None, Some(archive)) => (
+            SignatureKind::Archive,
+            &archive.signature,
+            archive.listing.as_bytes(),
+        ),
+        (None, None) => return Ok(None),
+    };
+
+    let keys: Vec<SigningKeyDb> = sqlx::query_as(
+        r#"SELECT "id", "owner", "name", "public_key", "created_at", "revoked_at" FROM signing_keys WHERE "owner"=? AND "revoked_at" IS NULL;"#,
+    )
+    .bind(owner_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    keys.into_iter()
+        .find(|key| signing::verify(&key.public_key, message, signature))
+        .map(|key| {
+            Some(SignatureRecord {
+                kind,
+                signature: STANDARD.encode(signature.to_bytes()),
+                key_id: key.id,
+            })
+        })
+        .ok_or(PublishError::InvalidSignature)
+}
+
+/// Runs the checks of [`create_package`] without storing anything.
+/// Returns whether the package name is still available.
+pub async fn dry_run_publish(
+    pool: Data<SqlitePool>,
+    package: &Package,
+    owner_id: Option<i64>,
+    file: &[u8],
+    signature: Option<&Signature>,
+) -> Result<bool, PublishError> {
+    let mut conn = pool.acquire().await?;
+    let version = package.manifest.version.to_string();
+
+    let available = check_publish(&mut conn, &package.manifest.name, &version, owner_id)
+        .await?
+        .is_none();
+    check_requirements(&mut conn, package).await?;
+
+    if let Some(owner_id) = owner_id {
+        verify_signature(&mut conn, owner_id, package, file, signature).await?;
+    }
+
+    Ok(available)
+}
+
+/// Publishes a package version.
+///
+/// The artifact is first written to a staging area. Only once every check
+/// passed and the database rows are written is it moved into place, right
+/// before the transaction is committed. Any failure leaves neither the file
+/// nor the rows behind, except for a crash before the commit, which leaves
+/// a file that is reported at startup and blocks publishing the version. Published versions are immutable, uploading the same
+/// version again fails with [`PublishError::VersionExists`].
 pub async fn create_package(
     pool: Data<SqlitePool>,
     package: Package,
     owner_id: i64,
     file: Bytes,
-) -> Result<SqliteQueryResult, Error> {
+    signature: Option<Signature>,
+) -> Result<(), PublishError> {
+    let signature = verify_signature(
+        &mut *pool.acquire().await?,
+        owner_id,
+        &package,
+        &file,
+        signature.as_ref(),
+    )
+    .await?;
+
+    let staged = staging_path(&package.manifest.name, &package.manifest.version);
+    let digest = Digest {
+        sha256: hex::encode(Sha256::digest(&file)),
+        size: file.len() as i64,
+    };
+
+    let res = match write(&staged, file).await {
+        Ok(_) => publish_staged(&pool, package, owner_id, digest, signature, &staged).await,
+        Err(e) => Err(e.into()),
+    };
+
+    if res.is_err() && staged.exists() {
+        // The publish error matters more than a leftover file
+        if let Err(e) = remove_file(&staged).await {
+            error!("Could not remove {}: {}", staged.display(), e);
+        }
+    }
+
+    res
+}
+
+async fn publish_staged(
+    pool: &SqlitePool,
+    package: Package,
+    owner_id: i64,
+    digest: Digest,
+    signature: Option<SignatureRecord>,
+    staged: &Path,
+) -> Result<(), PublishError> {
     let version = package.manifest.version.to_string();
     let bot_version = package.manifest.bot_version.to_string();
     let bot_type = package.manifest.bot_type.to_string();
-    let requires = to_string(&package.manifest.requires).unwrap();
-    let path = Path::new("data").join(&format!(
-        "{}-{}.zip",
-        &package.manifest.name, &package.manifest.version
-    ));
-    if path.exists() {
-        remove_file(&path).await?;
-    }
-    write(&path, file).await?;
+    let path = artifact_path(&package.manifest.name, &package.manifest.version);
 
-    let pkg: Option<PackageDb> =
-        sqlx::query_as(r#"SELECT "id", "owner" FROM packages WHERE "name"=?;"#)
-            .bind(&package.manifest.name)
-            .fetch_optional(&**pool)
-            .await?;
+    let mut tx = pool.begin().await?;
 
-    let pkg_id = {
-        if let Some(p) = pkg {
-            if p.owner != owner_id {
-                return Err(Error::RowNotFound); // anything really
-            }
-            p.id
-        } else {
-            sqlx::query(r#"INSERT INTO packages ("name", "owner") VALUES (?, ?);"#)
+    let requirements = check_requirements(&mut tx, &package).await?;
+    let mut requires = requirements.dependencies.clone();
+    requires.extend(requirements.platform.clone());
+
+    let pkg_id =
+        match check_publish(&mut tx, &package.manifest.name, &version, Some(owner_id)).await? {
+            Some(id) => id,
+            None => sqlx::query(r#"INSERT INTO packages ("name", "owner") VALUES (?, ?);"#)
                 .bind(&package.manifest.name)
                 .bind(owner_id)
-                .execute(&**pool)
+                .execute(&mut *tx)
                 .await?
-                .last_insert_rowid()
-        }
-    };
-
-    sqlx::query(r#"DELETE FROM versions WHERE "package"=? AND "version"=?;"#)
-        .bind(&pkg_id)
-        .bind(&version)
-        .execute(&**pool)
-        .await?;
+                .last_insert_rowid(),
+        };
 
-    sqlx::query(
-        r#"INSERT INTO versions ("package", "description", "short_description", "version", "author", "bot_type", "bot_version", "github", "requires") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?);"#,
+    let version_id = sqlx::query(
+        r#"INSERT INTO versions ("package", "description", "readme_text", "short_description", "version", "author", "bot_type", "bot_version", "github", "requires", "dependencies", "platform", "sha256", "size", "signature", "signature_kind", "signing_key", "release_notes", "published_at") VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, CURRENT_TIMESTAMP);"#,
     )
         .bind(pkg_id)
-        .bind(package.description)
+        .bind(&package.description)
+        .bind(description::to_text(&package.description))
         .bind(package.manifest.description)
         .bind(version)
         .bind(package.manifest.author)
         .bind(bot_type)
         .bind(bot_version)
         .bind(package.manifest.github)
-        .bind(requires)
-        .execute(&**pool)
-        .await
+        .bind(to_string(&requires).unwrap())
+        .bind(to_string(&requirements.dependencies).unwrap())
+        .bind(to_string(&requirements.platform).unwrap())
+        .bind(digest.sha256)
+        .bind(digest.size)
+        .bind(signature.as_ref().map(|s| s.signature.clone()))
+        .bind(signature.as_ref().map(|s| s.kind.as_str()))
+        .bind(signature.as_ref().map(|s| s.key_id))
+        .bind(&package.release_notes)
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+
+    insert_assets(&mut tx, version_id, &package.assets).await?;
+    insert_files(&mut tx, version_id, &package.files).await?;
+
+    for (name, req) in &requirements.dependencies {
+        sqlx::query(
+            r#"INSERT INTO version_dependencies ("version", "dependency", "requirement") VALUES (?, ?, ?);"#,
+        )
+        .bind(version_id)
+        .bind(name)
+        .bind(req.to_string())
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    // A file without a version row is left over from a crash, never replace it
+    if try_exists(&path).await? {
+        return Err(PublishError::ArtifactExists);
+    }
+    rename(staged, &path).await?;
+
+    if let Err(e) = tx.commit().await {
+        // The commit error matters more than a leftover file
+        if let Err(e) = remove_file(&path).await {
+            error!("Could not remove {}: {}", path.display(), e);
+        }
+        return Err(e.into());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_data_reports_violations() {
+    let manifest = crate::manifest::load_package_manifest(
+        r#"
+    name = "EXPORT MODULE"
+    description = "Exports stuff"
+    version = "1.0.0"
+    author = "Nadyita <nadyita@hodorraid.org>"
+    bot_type = "Nadybot"
+    bot_version = "^5.0.0"
+    "#,
+    )
+    .unwrap();
+    let package = Package {
+        manifest,
+        description: String::new(),
+        archive_signature: None,
+        assets: Vec::new(),
+        files: Vec::new(),
+        release_notes: Some("-".repeat(8001)),
+    };
+
+    assert_eq!(
+        validate_data(&package),
+        vec![
+            Violation::MaxLength {
+                field: "author",
+                limit: 30,
+                actual: 31,
+            },
+            Violation::MaxLength {
+                field: "release_notes",
+                limit: 8000,
+                actual: 8001,
+            },
+            Violation::Format {
+                field: "name",
+                expected: "alphanumeric characters, - or _",
+                actual: String::from("EXPORT MODULE"),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_search_query_matches_prefixes() {
+    assert_eq!(
+        search_query("export \"raid  "),
+        Some(String::from("\"export\"* \"\"\"raid\"*"))
+    );
+    assert_eq!(search_query("   "), None);
 }
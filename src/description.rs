@@ -1,10 +1,256 @@
 // README.md to HTML converter
+use ammonia::Builder;
 use pulldown_cmark::{html, Options, Parser};
 
-pub fn to_html(markdown: &str) -> String {
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Where relative links in a README point to.
+pub struct Links {
+    /// URL the paths of bundled assets are appended to.
+    pub assets_url: String,
+    /// Paths of the assets bundled with the package.
+    pub bundled: BTreeSet<String>,
+    /// The GitHub repository of the package, for links to files not bundled.
+    pub github: Option<String>,
+}
+
+impl Links {
+    fn rewrite(&self, element: &str, url: &str) -> Option<String> {
+        let (path, suffix) = relative_path(url)?;
+        let escaped = path.replace(' ', "%20");
+
+        if self.bundled.contains(&path) {
+            Some(format!("{}{}{}", self.assets_url, escaped, suffix))
+        } else {
+            let kind = if element == "img" { "raw" } else { "blob" };
+            self.github.as_ref().map(|repo| {
+                format!(
+                    "https://github.com/{}/{}/HEAD/{}{}",
+                    repo, kind, escaped, suffix
+                )
+            })
+        }
+    }
+}
+
+/// Builds the allow-list of the HTML a README may contain. On top of
+/// ammonia's defaults, it keeps what Markdown renders to: code block
+/// languages, table alignment and task list checkboxes.
+///
+/// `rewrite` is called with the element and value of every link and image
+/// source and may return a replacement.
+fn sanitizer<F>(rewrite: F) -> Builder<'static>
+where
+    F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static,
+{
+    let mut builder = Builder::default();
+    builder
+        .add_tags(["input"])
+        .add_tag_attributes("code", ["class"])
+        .add_tag_attributes("th", ["style"])
+        .add_tag_attributes("td", ["style"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .filter_style_properties(HashSet::from(["text-align"]))
+        .attribute_filter(
+            move |element, attribute, value| match (element, attribute) {
+                ("input", "type") if value != "checkbox" => None,
+                ("img", "src") | ("a", "href") => Some(
+                    rewrite(element, value)
+                        .map(Cow::Owned)
+                        .unwrap_or(Cow::Borrowed(value)),
+                ),
+                _ => Some(Cow::Borrowed(value)),
+            },
+        );
+    builder
+}
+
+/// Resolves a link relative to the package root, like GitHub does for
+/// READMEs. Returns the path and the query or fragment following it, or
+/// `None` for absolute URLs and links within the page.
+fn relative_path(url: &str) -> Option<(String, &str)> {
+    if let Some(colon) = url.find(':') {
+        let scheme = &url[..colon];
+        if !scheme.contains(['/', '?', '#']) {
+            return None;
+        }
+    }
+    if url.starts_with("//") {
+        return None;
+    }
+
+    let end = url.find(['?', '#']).unwrap_or(url.len());
+    let (path, suffix) = url.split_at(end);
+
+    let mut segments: Vec<String> = Vec::new();
+    for segment in percent_decode(path)?.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            segment => segments.push(segment.to_string()),
+        }
+    }
+
+    if segments.is_empty() {
+        None
+    } else {
+        Some((segments.join("/"), suffix))
+    }
+}
+
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn render(markdown: &str) -> String {
     let options = Options::all();
     let parser = Parser::new_ext(markdown, options);
     let mut html_output = String::new();
     html::push_html(&mut html_output, parser);
     html_output
 }
+
+/// Collects the package paths of all relative links and images in a README.
+pub fn relative_paths(markdown: &str) -> BTreeSet<String> {
+    let paths = Arc::new(Mutex::new(BTreeSet::new()));
+    let found = Arc::clone(&paths);
+    sanitizer(move |_, url| {
+        if let Some((path, _)) = relative_path(url) {
+            found.lock().unwrap().insert(path);
+        }
+        None
+    })
+    .clean(&render(markdown));
+
+    let paths = paths.lock().unwrap();
+    paths.clone()
+}
+
+/// Renders a README to HTML that is safe to embed in our pages. Relative
+/// links are rewritten according to `links`.
+pub fn to_html(markdown: &str, links: Option<Links>) -> String {
+    sanitizer(move |element, url| links.as_ref().and_then(|l| l.rewrite(element, url)))
+        .clean(&render(markdown))
+        .to_string()
+}
+
+/// Strips everything from rendered HTML that is not on the allow-list, like
+/// scripts, event handlers and `javascript:` links.
+pub fn sanitize(html: &str) -> String {
+    sanitizer(|_, _| None).clean(html).to_string()
+}
+
+/// Extracts the text of a rendered description for the search index.
+pub fn to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+        rest = match rest[start..].find('>') {
+            Some(end) => &rest[start + end + 1..],
+            None => "",
+        };
+    }
+    text.push_str(rest);
+
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[test]
+fn test_to_text() {
+    let html = to_html(
+        "# Export\n\nExports *all* the `<stuff>` & more.\n\n- one\n- two\n",
+        None,
+    );
+    assert_eq!(
+        to_text(&html),
+        "Export Exports all the <stuff> & more. one two"
+    );
+}
+
+#[test]
+fn test_to_html_sanitizes() {
+    let html = to_html(
+        "# Title\n\n<script>alert(1)</script>\n\n<img src=x onerror=alert(1)>\n\n[a](javascript:alert(1))\n\n\
+         | a | b |\n|:-:|---|\n| 1 | 2 |\n\n- [x] done\n\n```php\necho 1;\n```\n",
+        None,
+    );
+
+    assert!(!html.contains("script"));
+    assert!(!html.contains("onerror"));
+    assert!(!html.contains("javascript:"));
+    assert!(html.contains("<h1>Title</h1>"));
+    assert!(html.contains(r#"<th style="text-align:center">"#));
+    assert!(html.contains(r#"<input disabled="" type="checkbox" checked="">"#));
+    assert!(html.contains(r#"<code class="language-php">"#));
+}
+
+#[test]
+fn test_to_html_rewrites_relative_links() {
+    let markdown = "![shot](./docs/shot.png) ![logo](<img/my logo.png>) [guide](docs/GUIDE.md#setup) \
+                    [top](#top) [site](https://example.com/a.png)\n\n<img src=\"../../docs/x.png\">";
+
+    let paths: Vec<String> = relative_paths(markdown).into_iter().collect();
+    assert_eq!(
+        paths,
+        vec!["docs/GUIDE.md", "docs/shot.png", "img/my logo.png"]
+    );
+
+    let links = Links {
+        assets_url: String::from("/packages/EXPORT/1.0.0/assets/"),
+        bundled: vec![
+            String::from("docs/shot.png"),
+            String::from("img/my logo.png"),
+        ]
+        .into_iter()
+        .collect(),
+        github: Some(String::from("Nadybot/export")),
+    };
+    let html = to_html(markdown, Some(links));
+
+    assert!(html.contains(r#"src="/packages/EXPORT/1.0.0/assets/docs/shot.png""#));
+    assert!(html.contains(r#"src="/packages/EXPORT/1.0.0/assets/img/my%20logo.png""#));
+    assert!(
+        html.contains(r#"href="https://github.com/Nadybot/export/blob/HEAD/docs/GUIDE.md#setup""#)
+    );
+    assert!(html.contains(r##"href="#top""##));
+    assert!(html.contains(r#"href="https://example.com/a.png""#));
+    assert!(html.contains(r#"<img src="../../docs/x.png">"#));
+}
@@ -0,0 +1,310 @@
+// Compares two versions of a package
+use crate::{
+    files::{as_text, FileEntry},
+    manifest::PackageManifestDb,
+};
+
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use serde_json::{to_value, Value};
+use similar::TextDiff;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
+/// Largest file a unified diff is computed for.
+pub const MAX_DIFF_SIZE: usize = 256 * 1024;
+
+/// The manifest fields compared, named like in the package JSON.
+const MANIFEST_FIELDS: [&str; 5] = [
+    "short_description",
+    "author",
+    "bot_type",
+    "bot_version",
+    "github",
+];
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// A changed entry of the `requires` map, `None` if it is missing on that side.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct RequirementChange {
+    pub name: String,
+    pub old: Option<VersionReq>,
+    pub new: Option<VersionReq>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Added,
+    Removed,
+    Modified,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct FileChange {
+    pub path: String,
+    pub status: FileStatus,
+    pub old_size: Option<u64>,
+    pub new_size: Option<u64>,
+    /// Unified diff of the file, `None` for binary files and files larger
+    /// than `MAX_DIFF_SIZE`.
+    pub diff: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct VersionDiff {
+    pub name: String,
+    pub old: Version,
+    pub new: Version,
+    pub manifest: Vec<FieldChange>,
+    pub requires: Vec<RequirementChange>,
+    pub files: Vec<FileChange>,
+}
+
+fn manifest_changes(old: &PackageManifestDb, new: &PackageManifestDb) -> Vec<FieldChange> {
+    let old = to_value(old).unwrap();
+    let new = to_value(new).unwrap();
+
+    MANIFEST_FIELDS
+        .iter()
+        .filter(|field| old[field] != new[field])
+        .map(|field| FieldChange {
+            field,
+            old: old[field].clone(),
+            new: new[field].clone(),
+        })
+        .collect()
+}
+
+fn requirement_changes(old: &PackageManifestDb, new: &PackageManifestDb) -> Vec<RequirementChange> {
+    let old: BTreeMap<&str, &VersionReq> = old
+        .requires
+        .iter()
+        .map(|r| (r.name.as_str(), &r.version))
+        .collect();
+    let new: BTreeMap<&str, &VersionReq> = new
+        .requires
+        .iter()
+        .map(|r| (r.name.as_str(), &r.version))
+        .collect();
+    let names: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+
+    names
+        .into_iter()
+        .filter(|name| old.get(name) != new.get(name))
+        .map(|name| RequirementChange {
+            name: name.to_string(),
+            old: old.get(name).map(|&r| r.clone()),
+            new: new.get(name).map(|&r| r.clone()),
+        })
+        .collect()
+}
+
+fn unified_diff(path: &str, old: &[u8], new: &[u8]) -> Option<String> {
+    if old.len() > MAX_DIFF_SIZE || new.len() > MAX_DIFF_SIZE {
+        return None;
+    }
+    let (old, new) = (as_text(old)?, as_text(new)?);
+
+    let diff = TextDiff::configure()
+        .timeout(Duration::from_secs(1))
+        .diff_lines(old, new);
+    Some(
+        diff.unified_diff()
+            .header(&format!("a/{}", path), &format!("b/{}", path))
+            .to_string(),
+    )
+}
+
+impl FileChange {
+    /// Whether both sides are small enough to get a unified diff.
+    pub fn diffable(&self) -> bool {
+        [self.old_size, self.new_size]
+            .iter()
+            .all(|size| size.is_none_or(|size| size <= MAX_DIFF_SIZE as u64))
+    }
+}
+
+/// The files that differ between two listings, judged by their size and hash.
+/// The changes have no diffs yet, see [`add_diffs`].
+pub fn file_changes(old: &[FileEntry], new: &[FileEntry]) -> Vec<FileChange> {
+    let old: BTreeMap<&str, &FileEntry> = old.iter().map(|f| (f.path.as_str(), f)).collect();
+    let new: BTreeMap<&str, &FileEntry> = new.iter().map(|f| (f.path.as_str(), f)).collect();
+    let paths: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let (old, new) = (old.get(path), new.get(path));
+            let status = match (old, new) {
+                (None, Some(_)) => FileStatus::Added,
+                (Some(_), None) => FileStatus::Removed,
+                (Some(old), Some(new)) if old.size != new.size || old.sha256 != new.sha256 => {
+                    FileStatus::Modified
+                }
+                _ => return None,
+            };
+
+            Some(FileChange {
+                path: path.to_string(),
+                status,
+                old_size: old.map(|f| f.size),
+                new_size: new.map(|f| f.size),
+                diff: None,
+            })
+        })
+        .collect()
+}
+
+/// Adds unified diffs to `changes` from the contents read of both versions.
+/// Changes whose contents were not read get none.
+pub fn add_diffs(
+    changes: &mut [FileChange],
+    old: &BTreeMap<String, Vec<u8>>,
+    new: &BTreeMap<String, Vec<u8>>,
+) {
+    let empty = Vec::new();
+
+    for change in changes {
+        // A missing side diffs against an empty file
+        let old = match change.old_size {
+            Some(_) => old.get(&change.path),
+            None => Some(&empty),
+        };
+        let new = match change.new_size {
+            Some(_) => new.get(&change.path),
+            None => Some(&empty),
+        };
+
+        if let (Some(old), Some(new)) = (old, new) {
+            change.diff = unified_diff(&change.path, old, new);
+        }
+    }
+}
+
+/// Compares two versions of a package, with the `files` that changed.
+pub fn compare(
+    old: &PackageManifestDb,
+    new: &PackageManifestDb,
+    files: Vec<FileChange>,
+) -> VersionDiff {
+    VersionDiff {
+        name: new.name.clone(),
+        old: old.version.clone(),
+        new: new.version.clone(),
+        manifest: manifest_changes(old, new),
+        requires: requirement_changes(old, new),
+        files,
+    }
+}
+
+#[test]
+fn test_compare() {
+    use crate::manifest::Requirement;
+    use sha2::Digest;
+
+    let release =
+        |version: &str, bot_version: &str, requires: Vec<(&str, &str)>| PackageManifestDb {
+            bot_version: VersionReq::parse(bot_version).unwrap(),
+            requires: requires
+                .into_iter()
+                .map(|(name, version)| Requirement {
+                    name: name.to_string(),
+                    version: VersionReq::parse(version).unwrap(),
+                })
+                .collect(),
+            ..PackageManifestDb::test("EXPORT_MODULE", version)
+        };
+    let contents = |entries: &[(&str, &str)]| -> BTreeMap<String, Vec<u8>> {
+        entries
+            .iter()
+            .map(|(path, contents)| (path.to_string(), contents.as_bytes().to_vec()))
+            .collect()
+    };
+    let listing = |entries: &[(&str, &str)]| -> Vec<FileEntry> {
+        entries
+            .iter()
+            .map(|(path, contents)| FileEntry {
+                path: path.to_string(),
+                size: contents.len() as u64,
+                sha256: hex::encode(sha2::Sha256::digest(contents)),
+            })
+            .collect()
+    };
+
+    let old = release("1.0.0", "^5.0.0", vec![("ext-json", "*"), ("LIB", "^1")]);
+    let new = release("1.1.0", "^6.0.0", vec![("LIB", "^2"), ("UTIL", "^1")]);
+    let old_files = [("a.php", "one\ntwo\n"), ("b.php", "b\n"), ("c.txt", "c\n")];
+    let new_files = [("a.php", "one\nthree\n"), ("c.txt", "c\n"), ("d.bin", "\0")];
+    let mut changes = file_changes(&listing(&old_files), &listing(&new_files));
+    assert!(changes.iter().all(FileChange::diffable));
+    add_diffs(&mut changes, &contents(&old_files), &contents(&new_files));
+    let diff = compare(&old, &new, changes);
+
+    assert_eq!(
+        diff.manifest,
+        vec![FieldChange {
+            field: "bot_version",
+            old: Value::from("^5.0.0"),
+            new: Value::from("^6.0.0"),
+        }]
+    );
+    let requires: Vec<(&str, Option<String>, Option<String>)> = diff
+        .requires
+        .iter()
+        .map(|r| {
+            (
+                r.name.as_str(),
+                r.old.as_ref().map(|v| v.to_string()),
+                r.new.as_ref().map(|v| v.to_string()),
+            )
+        })
+        .collect();
+    assert_eq!(
+        requires,
+        vec![
+            ("LIB", Some(String::from("^1")), Some(String::from("^2"))),
+            ("UTIL", None, Some(String::from("^1"))),
+            ("ext-json", Some(String::from("*")), None),
+        ]
+    );
+    assert_eq!(
+        diff.files,
+        vec![
+            FileChange {
+                path: String::from("a.php"),
+                status: FileStatus::Modified,
+                old_size: Some(8),
+                new_size: Some(10),
+                diff: Some(String::from(
+                    "--- a/a.php\n+++ b/a.php\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n"
+                )),
+            },
+            FileChange {
+                path: String::from("b.php"),
+                status: FileStatus::Removed,
+                old_size: Some(2),
+                new_size: None,
+                diff: Some(String::from(
+                    "--- a/b.php\n+++ b/b.php\n@@ -1 +0,0 @@\n-b\n"
+                )),
+            },
+            FileChange {
+                path: String::from("d.bin"),
+                status: FileStatus::Added,
+                old_size: None,
+                new_size: Some(1),
+                diff: None,
+            },
+        ]
+    );
+}
@@ -0,0 +1,94 @@
+// JSON error responses of the API
+use crate::{
+    db::{PublishError, Violation},
+    package::ParseError,
+    signing::ARCHIVE_SIGNATURE_FILE,
+};
+
+use serde::Serialize;
+
+/// The body of every JSON error response.
+///
+/// `error` is a stable machine readable code, `message` is meant for humans.
+/// The remaining fields are only present for errors they apply to.
+#[derive(Serialize, Debug)]
+pub struct ApiError {
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+impl ApiError {
+    pub fn new(error: &'static str, message: impl ToString) -> Self {
+        Self {
+            error,
+            message: message.to_string(),
+            violations: Vec::new(),
+            file: None,
+            line: None,
+            column: None,
+        }
+    }
+}
+
+impl From<&ParseError> for ApiError {
+    fn from(e: &ParseError) -> Self {
+        let mut error = Self::new("invalid_package", e);
+
+        match e {
+            ParseError::ZipError(_) => error.error = "invalid_zip",
+            ParseError::MissingFile(name) => {
+                error.error = "missing_file";
+                error.file = Some(name);
+            }
+            ParseError::InvalidSignature => {
+                error.error = "invalid_signature";
+                error.file = Some(ARCHIVE_SIGNATURE_FILE);
+            }
+            ParseError::TOMLError { line, column, .. } => {
+                error.error = "invalid_manifest";
+                error.file = Some("aopkg.toml");
+                error.line = *line;
+                error.column = *column;
+            }
+            ParseError::Timeout => error.error = "timeout",
+        }
+
+        error
+    }
+}
+
+impl From<Vec<Violation>> for ApiError {
+    fn from(violations: Vec<Violation>) -> Self {
+        Self {
+            violations,
+            ..Self::new(
+                "validation_failed",
+                "Package format OK, but some fields violate the registry limits",
+            )
+        }
+    }
+}
+
+impl From<&PublishError> for ApiError {
+    fn from(e: &PublishError) -> Self {
+        match e {
+            PublishError::Forbidden => {
+                Self::new("forbidden", "You are not the owner of this package")
+            }
+            PublishError::VersionExists => Self::new("version_exists", e),
+            PublishError::InvalidSignature => Self::new("invalid_signature", e),
+            PublishError::MissingDependencies(_) => Self::new("missing_dependencies", e),
+            PublishError::ArtifactExists | PublishError::Database(_) | PublishError::Io(_) => {
+                Self::new("internal", "The package could not be stored")
+            }
+        }
+    }
+}
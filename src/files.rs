@@ -0,0 +1,363 @@
+// Browsing and viewing the files of published packages
+use crate::{db, description::escape_html, package::package_root};
+
+use lazy_static::lazy_static;
+use semver::Version;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::FromRow;
+use syntect::{
+    html::{ClassStyle, ClassedHTMLGenerator},
+    parsing::SyntaxSet,
+    util::LinesWithEndings,
+};
+use tokio::task::spawn_blocking;
+use zip::{result::ZipError, ZipArchive};
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{copy, ErrorKind, Read, Seek},
+};
+
+/// Largest file shown in the source viewer.
+pub const MAX_VIEW_SIZE: u64 = 512 * 1024;
+/// Largest combined size of the unpacked files of a package.
+pub const MAX_UNPACKED_SIZE: u64 = 64 * 1024 * 1024;
+
+lazy_static! {
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+}
+
+#[derive(Serialize, FromRow, Clone, Debug, PartialEq)]
+pub struct FileEntry {
+    /// Path relative to the package root.
+    pub path: String,
+    #[sqlx(try_from = "i64")]
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// A file or subdirectory shown when browsing a directory.
+#[derive(Debug, PartialEq)]
+pub struct DirEntry {
+    pub name: String,
+    pub path: String,
+    /// The file size, `None` for directories.
+    pub size: Option<u64>,
+}
+
+pub struct FileView {
+    pub size: u64,
+    pub sha256: String,
+    /// The highlighted contents, `None` for binary files and files larger
+    /// than `MAX_VIEW_SIZE`.
+    pub html: Option<String>,
+}
+
+/// Opens the stored archive of a version, `None` if there is none.
+fn open(name: &str, version: &Version) -> Result<Option<ZipArchive<File>>, ZipError> {
+    match File::open(db::artifact_path(name, version)) {
+        Ok(file) => Ok(Some(ZipArchive::new(file)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn read_blocking(
+    name: &str,
+    version: &Version,
+    paths: &[String],
+    limit: u64,
+) -> Result<Option<BTreeMap<String, Vec<u8>>>, ZipError> {
+    let mut zip = match open(name, version)? {
+        Some(zip) => zip,
+        None => return Ok(None),
+    };
+    let root = package_root(&zip);
+    let mut files = BTreeMap::new();
+
+    for path in paths {
+        let file = match zip.by_name(&format!("{}{}", root, path)) {
+            Ok(file) if !file.is_dir() => file,
+            Ok(_) | Err(ZipError::FileNotFound) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let mut contents = Vec::new();
+        file.take(limit).read_to_end(&mut contents)?;
+        files.insert(path.clone(), contents);
+    }
+
+    Ok(Some(files))
+}
+
+/// Reads the first `limit` bytes of the files at `paths` in the archive of a
+/// version. Files the archive does not have are left out. Returns `None` if
+/// the archive is not stored.
+pub async fn read(
+    name: String,
+    version: Version,
+    paths: Vec<String>,
+    limit: u64,
+) -> Result<Option<BTreeMap<String, Vec<u8>>>, ZipError> {
+    spawn_blocking(move || read_blocking(&name, &version, &paths, limit))
+        .await
+        .unwrap()
+}
+
+/// Lists and hashes the files of an archive, sorted by path. Archives whose
+/// files add up to more than `MAX_UNPACKED_SIZE` according to the central
+/// directory are rejected, and no file is read past its recorded size.
+pub fn entries<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    root: &str,
+) -> Result<Vec<FileEntry>, ZipError> {
+    let mut files = Vec::new();
+    let mut total: u64 = 0;
+
+    for i in 0..zip.len() {
+        let file = zip.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let size = file.size();
+        total = total.saturating_add(size);
+        if total > MAX_UNPACKED_SIZE {
+            return Err(ZipError::UnsupportedArchive(
+                "the unpacked files are too large",
+            ));
+        }
+
+        let path = file
+            .name()
+            .strip_prefix(root)
+            .unwrap_or(file.name())
+            .to_string();
+        let mut hasher = Sha256::new();
+        if copy(&mut file.take(size + 1), &mut hasher)? != size {
+            return Err(ZipError::InvalidArchive(
+                "file size does not match the central directory",
+            ));
+        }
+
+        files.push(FileEntry {
+            path,
+            size,
+            sha256: hex::encode(hasher.finalize()),
+        });
+    }
+
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn list_blocking(name: &str, version: &Version) -> Result<Option<Vec<FileEntry>>, ZipError> {
+    let mut zip = match open(name, version)? {
+        Some(zip) => zip,
+        None => return Ok(None),
+    };
+    let root = package_root(&zip);
+
+    entries(&mut zip, &root).map(Some)
+}
+
+/// Lists the files in the stored archive of a version. Returns `None` if the
+/// archive is not stored. Published versions have their listing in the
+/// database, see [`db::get_files`].
+pub async fn list(name: String, version: Version) -> Result<Option<Vec<FileEntry>>, ZipError> {
+    spawn_blocking(move || list_blocking(&name, &version))
+        .await
+        .unwrap()
+}
+
+/// The contents of a file as text, `None` for binary files.
+pub fn as_text(contents: &[u8]) -> Option<&str> {
+    std::str::from_utf8(contents)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+}
+
+/// Highlights PHP, Python and SQL files, other text is only escaped.
+fn highlight(path: &str, text: &str) -> String {
+    let extension = path.rsplit_once('.').map_or("", |(_, e)| e);
+    let syntax = match extension.to_ascii_lowercase().as_str() {
+        "php" | "py" | "sql" => SYNTAXES.find_syntax_by_extension(extension),
+        _ => None,
+    };
+    let syntax = match syntax {
+        Some(syntax) => syntax,
+        None => return escape_html(text),
+    };
+
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(
+        syntax,
+        &SYNTAXES,
+        ClassStyle::SpacedPrefixed { prefix: "hl-" },
+    );
+    for line in LinesWithEndings::from(text) {
+        if generator
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            return escape_html(text);
+        }
+    }
+    generator.finalize()
+}
+
+fn view_blocking(
+    name: &str,
+    version: &Version,
+    entry: FileEntry,
+) -> Result<Option<FileView>, ZipError> {
+    if entry.size > MAX_VIEW_SIZE {
+        return Ok(Some(FileView {
+            size: entry.size,
+            sha256: entry.sha256,
+            html: None,
+        }));
+    }
+
+    let mut zip = match open(name, version)? {
+        Some(zip) => zip,
+        None => return Ok(None),
+    };
+    let root = package_root(&zip);
+    let file = match zip.by_name(&format!("{}{}", root, entry.path)) {
+        Ok(file) if !file.is_dir() => file,
+        Ok(_) | Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut contents = Vec::new();
+    file.take(MAX_VIEW_SIZE).read_to_end(&mut contents)?;
+    let html = as_text(&contents).map(|text| highlight(&entry.path, text));
+
+    Ok(Some(FileView {
+        size: entry.size,
+        sha256: entry.sha256,
+        html,
+    }))
+}
+
+/// Reads a listed file of a version for the source viewer. Returns `None` if
+/// the archive is not stored or has no such file.
+pub async fn view(
+    name: String,
+    version: Version,
+    entry: FileEntry,
+) -> Result<Option<FileView>, ZipError> {
+    spawn_blocking(move || view_blocking(&name, &version, entry))
+        .await
+        .unwrap()
+}
+
+/// The direct children of directory `dir` (empty for the package root),
+/// directories first. Returns `None` if there is no such directory.
+pub fn directory(files: &[FileEntry], dir: &str) -> Option<Vec<DirEntry>> {
+    let prefix = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", dir.trim_end_matches('/'))
+    };
+
+    let mut dirs: Vec<DirEntry> = Vec::new();
+    let mut entries = Vec::new();
+    for file in files {
+        let rest = match file.path.strip_prefix(&prefix) {
+            Some(rest) => rest,
+            None => continue,
+        };
+
+        match rest.split_once('/') {
+            Some((name, _)) => {
+                if dirs.last().is_none_or(|d| d.name != name) {
+                    dirs.push(DirEntry {
+                        name: name.to_string(),
+                        path: format!("{}{}", prefix, name),
+                        size: None,
+                    });
+                }
+            }
+            None => entries.push(DirEntry {
+                name: rest.to_string(),
+                path: file.path.clone(),
+                size: Some(file.size),
+            }),
+        }
+    }
+
+    if dirs.is_empty() && entries.is_empty() && !prefix.is_empty() {
+        return None;
+    }
+
+    dirs.extend(entries);
+    Some(dirs)
+}
+
+/// The links leading from the package root to `path`, as names and paths.
+pub fn breadcrumbs(path: &str) -> Vec<(String, String)> {
+    let mut crumbs = Vec::new();
+    let mut current = String::new();
+
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        if !current.is_empty() {
+            current.push('/');
+        }
+        current.push_str(segment);
+        crumbs.push((segment.to_string(), current.clone()));
+    }
+
+    crumbs
+}
+
+#[test]
+fn test_directory() {
+    let file = |path: &str| FileEntry {
+        path: path.to_string(),
+        size: 1,
+        sha256: String::new(),
+    };
+    let files = vec![
+        file("README.md"),
+        file("aopkg.toml"),
+        file("src/Export.php"),
+        file("src/sql/001_init.sql"),
+    ];
+    let entry = |name: &str, path: &str, size| DirEntry {
+        name: name.to_string(),
+        path: path.to_string(),
+        size,
+    };
+
+    assert_eq!(
+        directory(&files, ""),
+        Some(vec![
+            entry("src", "src", None),
+            entry("README.md", "README.md", Some(1)),
+            entry("aopkg.toml", "aopkg.toml", Some(1)),
+        ])
+    );
+    assert_eq!(
+        directory(&files, "src/"),
+        Some(vec![
+            entry("sql", "src/sql", None),
+            entry("Export.php", "src/Export.php", Some(1)),
+        ])
+    );
+    assert_eq!(directory(&files, "docs"), None);
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (path, contents) in [("EXPORT/src/a.php", "<?php"), ("EXPORT/README.md", "")] {
+        writer.start_file(path, Default::default()).unwrap();
+        std::io::Write::write_all(&mut writer, contents.as_bytes()).unwrap();
+    }
+    let mut zip = ZipArchive::new(writer.finish().unwrap()).unwrap();
+    let listed = entries(&mut zip, "EXPORT/").unwrap();
+    assert_eq!(listed[0].path, "README.md");
+    assert_eq!(listed[1].size, 5);
+    assert_eq!(listed[1].sha256, hex::encode(Sha256::digest("<?php")));
+    assert!(highlight("src/Export.php", "<?php\n$a = 1;\n").contains("hl-variable"));
+}
@@ -0,0 +1,280 @@
+// Persistent queue importing GitHub releases in the background
+use crate::{
+    db::{self, PublishError, QueuedJob, Violation},
+    package::{self, ParseError},
+    webhook::{self, Release},
+};
+
+use actix_web::{rt::time::sleep, web::Data};
+use awc::Client;
+use log::{debug, error, info};
+use serde::Serialize;
+use sqlx::{ColumnIndex, Decode, Error as SqlxError, FromRow, Row, SqlitePool, Type};
+
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::Cursor,
+    time::Duration,
+};
+
+/// Attempts after which a job that keeps failing is given up.
+pub const MAX_ATTEMPTS: i64 = 5;
+/// Delay before the first retry, every further retry waits four times longer.
+const FIRST_RETRY: Duration = Duration::from_secs(30);
+/// How often the worker looks for due jobs when the queue is empty.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// Waiting to be run, possibly after failed attempts.
+    Queued,
+    Running,
+    Succeeded,
+    /// Failed permanently or too often, only runs again when retried.
+    Dead,
+}
+
+impl Display for JobStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Queued => write!(f, "queued"),
+            Self::Running => write!(f, "running"),
+            Self::Succeeded => write!(f, "succeeded"),
+            Self::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+impl TryFrom<&str> for JobStatus {
+    type Error = &'static str;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "queued" => Ok(Self::Queued),
+            "running" => Ok(Self::Running),
+            "succeeded" => Ok(Self::Succeeded),
+            "dead" => Ok(Self::Dead),
+            _ => Err("Unknown job status"),
+        }
+    }
+}
+
+/// An import of a GitHub release as shown to the package owner.
+#[derive(Serialize)]
+pub struct Job {
+    pub id: i64,
+    /// The release tag.
+    pub tag: String,
+    /// The webhook delivery that queued the job.
+    pub delivery: Option<String>,
+    pub status: JobStatus,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    /// When a queued job runs next.
+    pub run_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Job {
+    /// Whether the owner can retry the job now. Dead jobs and jobs waiting
+    /// for their next attempt can be.
+    pub fn can_retry(&self) -> bool {
+        match self.status {
+            JobStatus::Dead => true,
+            JobStatus::Queued => self.attempts > 0,
+            _ => false,
+        }
+    }
+}
+
+impl<'r, 's, R> FromRow<'r, R> for Job
+where
+    R: Row,
+    &'s str: ColumnIndex<R>,
+    String: Type<R::Database> + Decode<'r, R::Database>,
+    i64: Type<R::Database> + Decode<'r, R::Database>,
+{
+    #[inline]
+    fn from_row(row: &'r R) -> Result<Self, SqlxError> {
+        let status: String = row.try_get("status")?;
+
+        Ok(Self {
+            id: row.try_get("id")?,
+            tag: row.try_get("tag")?,
+            delivery: row.try_get("delivery")?,
+            status: JobStatus::try_from(status.as_str())
+                .map_err(|e| SqlxError::Decode(e.into()))?,
+            attempts: row.try_get("attempts")?,
+            last_error: row.try_get("last_error")?,
+            run_at: row.try_get("run_at")?,
+            created_at: row.try_get("created_at")?,
+            updated_at: row.try_get("updated_at")?,
+        })
+    }
+}
+
+/// Why an import failed. Only temporary failures are retried automatically.
+#[derive(Debug, PartialEq)]
+enum JobError {
+    /// The download or the database failed, trying again may help.
+    Temporary(String),
+    /// The release itself is unusable, trying again gives the same result.
+    Permanent(String),
+}
+
+impl Display for JobError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Temporary(message) | Self::Permanent(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<ParseError> for JobError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Timeout => Self::Temporary(e.to_string()),
+            _ => Self::Permanent(e.to_string()),
+        }
+    }
+}
+
+impl From<PublishError> for JobError {
+    fn from(e: PublishError) -> Self {
+        match e {
+            PublishError::Database(_) | PublishError::Io(_) => Self::Temporary(e.to_string()),
+            _ => Self::Permanent(e.to_string()),
+        }
+    }
+}
+
+impl From<Vec<Violation>> for JobError {
+    fn from(violations: Vec<Violation>) -> Self {
+        let fields: Vec<&str> = violations
+            .iter()
+            .map(|v| match v {
+                Violation::MaxLength { field, .. }
+                | Violation::MaxEntries { field, .. }
+                | Violation::Format { field, .. } => *field,
+            })
+            .collect();
+        Self::Permanent(format!(
+            "some fields violate the registry limits: {}",
+            fields.join(", ")
+        ))
+    }
+}
+
+/// How long to wait before the next attempt of a job that failed `attempts` times.
+fn backoff(attempts: i64) -> Duration {
+    let exponent = attempts.clamp(1, MAX_ATTEMPTS) - 1;
+    FIRST_RETRY * 4u32.pow(exponent as u32)
+}
+
+/// Downloads the GitHub release of a job and publishes it as a new version
+/// of the job's package.
+async fn import(
+    release: &Release,
+    job: &QueuedJob,
+    client: Data<Client>,
+    pool: Data<SqlitePool>,
+) -> Result<(), JobError> {
+    let url = release.zip_url(&job.repository).ok_or_else(|| {
+        JobError::Permanent(format!(
+            "release {} has no ZIP from {}",
+            release.tag_name, job.repository
+        ))
+    })?;
+    let payload = webhook::download_release(url, client).await.map_err(|e| {
+        JobError::Temporary(format!(
+            "could not download release {}: {}",
+            release.tag_name, e
+        ))
+    })?;
+
+    let mut pkg = package::try_parse(Cursor::new(payload.clone())).await?;
+    if pkg.manifest.name != job.package {
+        return Err(JobError::Permanent(format!(
+            "the release contains {}, not {}",
+            pkg.manifest.name, job.package
+        )));
+    }
+    release
+        .check_version(&pkg.manifest.version)
+        .map_err(JobError::Permanent)?;
+    if let Some(notes) = release.notes() {
+        pkg.release_notes = Some(notes);
+    }
+
+    let violations = db::validate_data(&pkg);
+    if !violations.is_empty() {
+        return Err(violations.into());
+    }
+
+    db::create_package(pool, pkg, job.owner, payload, None).await?;
+    Ok(())
+}
+
+/// Runs a claimed job and records its outcome.
+async fn process(job: QueuedJob, client: Data<Client>, pool: Data<SqlitePool>) {
+    let result = match serde_json::from_str::<Release>(&job.release) {
+        Ok(release) => import(&release, &job, client, pool.clone()).await,
+        Err(e) => Err(JobError::Permanent(format!("invalid release: {}", e))),
+    };
+
+    let res = match result {
+        Ok(()) => {
+            info!("Job {}: release {} imported", job.id, job.tag);
+            db::finish_job(pool, job.id).await
+        }
+        Err(JobError::Temporary(message)) if job.attempts < MAX_ATTEMPTS => {
+            let delay = backoff(job.attempts);
+            debug!(
+                "Job {}: attempt {} failed, retrying in {:?}: {}",
+                job.id, job.attempts, delay, message
+            );
+            db::reschedule_job(pool, job.id, &message, delay).await
+        }
+        Err(e) => {
+            info!("Job {}: giving up on release {}: {}", job.id, job.tag, e);
+            db::bury_job(pool, job.id, &e.to_string()).await
+        }
+    };
+
+    if let Err(e) = res {
+        error!("Job {}: could not record the outcome: {}", job.id, e);
+    }
+}
+
+/// Works through the job queue, one job at a time, until the server stops.
+pub async fn run(client: Data<Client>, pool: Data<SqlitePool>) {
+    loop {
+        match db::claim_job(pool.clone()).await {
+            Ok(Some(job)) => process(job, client.clone(), pool.clone()).await,
+            Ok(None) => sleep(POLL_INTERVAL).await,
+            Err(e) => {
+                error!("Could not claim a job: {}", e);
+                sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let delays: Vec<u64> = (1..=MAX_ATTEMPTS).map(|n| backoff(n).as_secs()).collect();
+    assert_eq!(delays, vec![30, 120, 480, 1920, 7680]);
+    assert_eq!(backoff(0), FIRST_RETRY);
+
+    assert_eq!(
+        JobError::from(PublishError::VersionExists),
+        JobError::Permanent(String::from("version has already been published"))
+    );
+    assert_eq!(
+        JobError::from(ParseError::Timeout),
+        JobError::Temporary(String::from("parsing the package took too long"))
+    );
+}
@@ -0,0 +1,165 @@
+// Sorting, cursor pagination and field selection for package listings
+use crate::manifest::PackageManifestDb;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use serde_json::{to_value, Map, Value};
+
+pub const DEFAULT_LIMIT: usize = 50;
+pub const MAX_LIMIT: usize = 200;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Sort {
+    /// By package name, then newest version first.
+    #[default]
+    Name,
+    /// Most recently published first.
+    Updated,
+    /// Most downloaded first.
+    Downloads,
+}
+
+/// Position in a listing, pointing at the last item of the previous page.
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct Cursor {
+    pub sort: Sort,
+    pub name: String,
+    pub version: Version,
+    pub published_at: Option<String>,
+    pub downloads: i64,
+}
+
+impl Cursor {
+    pub fn at(sort: Sort, item: &PackageManifestDb) -> Self {
+        Self {
+            sort,
+            name: item.name.clone(),
+            version: item.version.clone(),
+            published_at: item.published_at.clone(),
+            downloads: item.downloads,
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap())
+    }
+
+    pub fn decode(input: &str) -> Option<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(input).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Page<T> {
+    pub packages: Vec<T>,
+    /// Pass as `cursor` to get the next page, missing on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Builds a page from up to `limit + 1` sorted items. The extra item only
+/// shows that another page follows.
+pub fn page(
+    mut items: Vec<PackageManifestDb>,
+    sort: Sort,
+    limit: usize,
+) -> Page<PackageManifestDb> {
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|item| Cursor::at(sort, item).encode())
+    } else {
+        None
+    };
+
+    Page {
+        packages: items,
+        next_cursor,
+    }
+}
+
+/// Keeps only the requested top-level fields of each item. Returns the first
+/// unknown field name on failure.
+pub fn select_fields<T: Serialize>(items: &[T], fields: &[&str]) -> Result<Vec<Value>, String> {
+    items
+        .iter()
+        .map(|item| {
+            let mut object = match to_value(item).unwrap() {
+                Value::Object(object) => object,
+                _ => unreachable!("listings only contain objects"),
+            };
+
+            let mut selected = Map::new();
+            for field in fields {
+                match object.remove(*field) {
+                    Some(value) => selected.insert(field.to_string(), value),
+                    None => return Err(field.to_string()),
+                };
+            }
+
+            Ok(Value::Object(selected))
+        })
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_paginate_by_downloads() {
+    use actix_web::web::Data;
+
+    let pool = crate::db::test_pool().await;
+    sqlx::query(
+        r#"INSERT INTO packages ("id", "owner", "name") VALUES (1, 1, 'EXPORT'), (2, 1, 'RAID');
+        INSERT INTO versions ("id", "package", "description", "short_description", "version", "author", "bot_type", "bot_version")
+            VALUES (1, 1, '', '', '1.0.0', 'Me', 'Nadybot', '^5.0.0'), (2, 1, '', '', '1.1.0', 'Me', 'Nadybot', '^5.0.0'),
+            (3, 2, '', '', '1.0.0', 'Me', 'Nadybot', '^6.0.0');
+        INSERT INTO download_stats ("version", "day", "count") VALUES (1, '2026-10-01', 7), (2, '2026-10-01', 3), (3, '2026-10-01', 3);"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let pool = Data::new(pool);
+
+    let items = crate::db::get_packages_page(pool.clone(), Sort::Downloads, None, None, None, 2)
+        .await
+        .unwrap();
+    let first = page(items, Sort::Downloads, 2);
+    let names: Vec<(&str, String)> = first
+        .packages
+        .iter()
+        .map(|p| (p.name.as_str(), p.version.to_string()))
+        .collect();
+    assert_eq!(
+        names,
+        vec![
+            ("EXPORT", String::from("1.0.0")),
+            ("EXPORT", String::from("1.1.0"))
+        ]
+    );
+
+    let cursor = Cursor::decode(&first.next_cursor.unwrap()).unwrap();
+    let items =
+        crate::db::get_packages_page(pool.clone(), cursor.sort, Some(&cursor), None, None, 2)
+            .await
+            .unwrap();
+    let second = page(items, Sort::Downloads, 2);
+    assert_eq!(second.packages.len(), 1);
+    assert_eq!(second.packages[0].name, "RAID");
+    assert!(second.next_cursor.is_none());
+
+    // Requirements are matched after sorting, the only match is on the last page
+    let bot_version = Version::new(6, 1, 0);
+    let items = crate::db::get_packages_page(pool, Sort::Name, None, None, Some(&bot_version), 1)
+        .await
+        .unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].name, "RAID");
+
+    let fields = select_fields(&second.packages, &["name", "downloads"]).unwrap();
+    assert_eq!(fields[0].to_string(), r#"{"downloads":3,"name":"RAID"}"#);
+    assert_eq!(
+        select_fields(&second.packages, &["nope"]).unwrap_err(),
+        "nope"
+    );
+}
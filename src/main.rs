@@ -2,14 +2,28 @@ use actix_files::{Files, NamedFile};
 use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
 use actix_web::{
     cookie::Key,
-    get, middleware, post,
+    delete, get,
+    http::{
+        header::{
+            HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_SECURITY_POLICY, ETAG, IF_NONE_MATCH,
+            X_CONTENT_TYPE_OPTIONS,
+        },
+        StatusCode,
+    },
+    middleware, post,
     web::{self, Data},
     App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use askama::Template;
+use auth::Scope;
 use awc::Client;
-use log::debug;
-use semver::Version;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::Signature;
+use error::ApiError;
+use log::{debug, error};
+use manifest::BotType;
+use semver::{Version, VersionReq};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize};
 use serde_json::to_string_pretty;
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqlitePoolOptions},
@@ -17,45 +31,181 @@ use sqlx::{
 };
 
 use std::{
-    env::{set_var, var},
+    env::{args, set_var, var},
     io::Cursor,
-    path::Path,
+    process::exit,
     str::FromStr,
 };
 
+mod admin;
+mod auth;
+mod changelog;
 mod db;
 mod description;
+mod diff;
+mod error;
+mod files;
+mod jobs;
+mod listing;
 mod manifest;
 mod oauth;
 mod package;
+mod resolver;
+mod signing;
+mod stats;
 mod templates;
+mod updates;
 mod webhook;
 
+const SIGNATURE_HEADER: &str = "X-Aopkg-Signature";
+
 #[post("/upload")]
 async fn upload_package(
+    req: HttpRequest,
     payload: web::Bytes,
     pool: web::Data<SqlitePool>,
     session: Session,
 ) -> impl Responder {
-    if let Ok(Some(id)) = session.get::<i64>("id") {
-        let cur = Cursor::new(payload.clone());
+    let id = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+    let signature = match detached_signature(&req) {
+        Ok(signature) => signature,
+        Err(res) => return res,
+    };
+    let cur = Cursor::new(payload.clone());
 
-        match package::try_parse(cur).await {
-            Ok(pkg) => {
-                if !db::validate_data(&pkg) {
-                    return HttpResponse::BadRequest()
-                        .body("Package format OK, but parts too long");
-                }
-
-                return match db::create_package(pool, pkg, id, payload).await {
-                    Ok(_) => HttpResponse::Created().finish(),
-                    Err(_) => HttpResponse::Forbidden().finish(),
-                };
+    match package::try_parse(cur).await {
+        Ok(pkg) => {
+            let violations = db::validate_data(&pkg);
+            if !violations.is_empty() {
+                return HttpResponse::BadRequest().json(ApiError::from(violations));
             }
-            Err(e) => return HttpResponse::BadRequest().body(format!("{:?}", e)),
-        };
-    } else {
-        HttpResponse::Unauthorized().finish()
+
+            publish_response(db::create_package(pool, pkg, id, payload, signature).await)
+        }
+        Err(e) => HttpResponse::BadRequest().json(ApiError::from(&e)),
+    }
+}
+
+/// Reads the optional detached signature of an upload.
+fn detached_signature(req: &HttpRequest) -> Result<Option<Signature>, HttpResponse> {
+    match req.headers().get(SIGNATURE_HEADER) {
+        Some(value) => match signing::decode_signature(value.as_bytes()) {
+            Some(signature) => Ok(Some(signature)),
+            None => Err(HttpResponse::BadRequest().json(ApiError::new(
+                "invalid_signature",
+                format!(
+                    "{} is not a base64 encoded ed25519 signature",
+                    SIGNATURE_HEADER
+                ),
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+#[derive(Serialize)]
+struct Validation {
+    valid: bool,
+    manifest: Option<manifest::PackageManifest>,
+    readme: Option<String>,
+    release_notes: Option<String>,
+    errors: Vec<ApiError>,
+    warnings: Vec<ApiError>,
+}
+
+#[post("/api/validate")]
+async fn validate_package(
+    req: HttpRequest,
+    payload: web::Bytes,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    let signature = match detached_signature(&req) {
+        Ok(signature) => signature,
+        Err(res) => return res,
+    };
+
+    let owner = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => Some(id),
+        Err(_) => {
+            warnings.push(ApiError::new(
+                "unauthorized",
+                "You are not authenticated, package ownership was not checked",
+            ));
+            None
+        }
+    };
+
+    let pkg = match package::try_parse(Cursor::new(payload.clone())).await {
+        Ok(pkg) => pkg,
+        Err(e) => {
+            errors.push(ApiError::from(&e));
+            return HttpResponse::Ok().json(Validation {
+                valid: false,
+                manifest: None,
+                readme: None,
+                release_notes: None,
+                errors,
+                warnings,
+            });
+        }
+    };
+
+    let violations = db::validate_data(&pkg);
+    if !violations.is_empty() {
+        errors.push(ApiError::from(violations));
+    }
+
+    match db::dry_run_publish(pool, &pkg, owner, &payload, signature.as_ref()).await {
+        Ok(true) => warnings.push(ApiError::new(
+            "new_package",
+            format!(
+                "{} is not registered yet, publishing will make you its owner",
+                pkg.manifest.name
+            ),
+        )),
+        Ok(false) => {}
+        Err(
+            e @ (db::PublishError::Forbidden
+            | db::PublishError::VersionExists
+            | db::PublishError::InvalidSignature
+            | db::PublishError::MissingDependencies(_)),
+        ) => errors.push(ApiError::from(&e)),
+        Err(e) => {
+            error!("Failed to check package: {}", e);
+            return HttpResponse::InternalServerError().json(ApiError::from(&e));
+        }
+    }
+
+    HttpResponse::Ok().json(Validation {
+        valid: errors.is_empty(),
+        manifest: Some(pkg.manifest),
+        readme: Some(pkg.description),
+        release_notes: pkg.release_notes,
+        errors,
+        warnings,
+    })
+}
+
+fn publish_response(res: Result<(), db::PublishError>) -> HttpResponse {
+    match res {
+        Ok(_) => HttpResponse::Created().finish(),
+        Err(e @ db::PublishError::Forbidden) => HttpResponse::Forbidden().json(ApiError::from(&e)),
+        Err(e @ db::PublishError::VersionExists) => {
+            HttpResponse::Conflict().json(ApiError::from(&e))
+        }
+        Err(
+            e @ (db::PublishError::InvalidSignature | db::PublishError::MissingDependencies(_)),
+        ) => HttpResponse::BadRequest().json(ApiError::from(&e)),
+        Err(e) => {
+            error!("Failed to publish package: {}", e);
+            HttpResponse::InternalServerError().json(ApiError::from(&e))
+        }
     }
 }
 
@@ -79,7 +229,7 @@ async fn get_package_versions(
     name: web::Path<String>,
     pool: web::Data<SqlitePool>,
 ) -> impl Responder {
-    let packages = db::get_package_versions(pool, &name)
+    let packages = db::get_package_versions(pool, &name, false)
         .await
         .expect("DB error");
 
@@ -92,40 +242,756 @@ async fn get_package_versions(
     }
 }
 
-#[get("/api/packages")]
-async fn get_all_package_data(pool: web::Data<SqlitePool>) -> impl Responder {
-    let packages = db::get_all_packages(pool).await.expect("DB error");
-    HttpResponse::Ok()
-        .content_type("application/json")
-        .body(to_string_pretty(&packages).unwrap())
+#[get("/api/packages/{name}/dependents")]
+async fn get_package_dependents(
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+        .is_none()
+    {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let dependents = db::get_dependents(pool, &name).await.expect("DB error");
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_string_pretty(&dependents).unwrap())
+}
+
+#[derive(Deserialize)]
+struct StatsQuery {
+    days: Option<u32>,
+}
+
+#[get("/api/packages/{name}/stats")]
+async fn get_package_stats(
+    name: web::Path<String>,
+    query: web::Query<StatsQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let days = query
+        .days
+        .unwrap_or(stats::DEFAULT_DAYS)
+        .clamp(1, stats::MAX_DAYS);
+
+    match stats::load(pool, &name, days).await.expect("DB error") {
+        Some(stats) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(to_string_pretty(&stats).unwrap()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Serialize)]
+struct WebhookSecret {
+    secret: String,
+}
+
+#[post("/api/packages/{name}/webhook-secret")]
+async fn create_webhook_secret(
+    req: HttpRequest,
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::ManageWebhooks).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    match db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            let secret = webhook::generate_secret();
+            db::set_webhook_secret(pool, p.id, Some(&secret))
+                .await
+                .expect("DB error");
+            HttpResponse::Ok().json(WebhookSecret { secret })
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[delete("/api/packages/{name}/webhook-secret")]
+async fn delete_webhook_secret(
+    req: HttpRequest,
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::ManageWebhooks).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    match db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            db::set_webhook_secret(pool, p.id, None)
+                .await
+                .expect("DB error");
+            HttpResponse::NoContent().finish()
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/api/packages/{name}/jobs")]
+async fn get_package_jobs(
+    req: HttpRequest,
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    match db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            let jobs = db::get_jobs(pool, p.id).await.expect("DB error");
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(to_string_pretty(&jobs).unwrap())
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[post("/api/packages/{name}/jobs/{id}/retry")]
+async fn retry_package_job(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    let p = match db::get_package(pool.clone(), &path.0)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => p,
+        Some(_) => return HttpResponse::Forbidden().finish(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if db::retry_job(pool.clone(), p.id, path.1)
+        .await
+        .expect("DB error")
+    {
+        return HttpResponse::NoContent().finish();
+    }
+    match db::get_job(pool, p.id, path.1).await.expect("DB error") {
+        Some(job) => HttpResponse::Conflict().json(ApiError::new(
+            "job_not_retryable",
+            format!("job {} is {} and cannot be retried", job.id, job.status),
+        )),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/api/packages/{name}/deliveries")]
+async fn get_package_deliveries(
+    req: HttpRequest,
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    match db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            let deliveries = db::get_deliveries(pool, p.id).await.expect("DB error");
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .body(to_string_pretty(&deliveries).unwrap())
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Handles a logged delivery again, as if GitHub had sent it once more. It
+/// was verified when it was received, so neither the signature nor the
+/// delivery ID are checked.
+#[post("/api/packages/{name}/deliveries/{id}/redeliver")]
+async fn redeliver_package_delivery(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match auth::authorize(&req, &session, &pool, Scope::Publish).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    let p = match db::get_package(pool.clone(), &path.0)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => p,
+        Some(_) => return HttpResponse::Forbidden().finish(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let delivery = match db::get_delivery(pool.clone(), p.id, path.1)
+        .await
+        .expect("DB error")
+    {
+        Some(delivery) => delivery,
+        None => return HttpResponse::NotFound().finish(),
+    };
+    let payload = match &delivery.payload {
+        Some(payload) => payload,
+        None => {
+            return HttpResponse::Conflict().json(ApiError::new(
+                "not_redeliverable",
+                "only deliveries with a valid signature can be redelivered",
+            ))
+        }
+    };
+
+    // The delivery is handled for this package only, if it is still for it
+    let target: webhook::WebhookTarget = match serde_json::from_str(payload) {
+        Ok(target) => target,
+        Err(e) => {
+            return HttpResponse::Conflict().json(ApiError::new(
+                "not_redeliverable",
+                format!("the delivery cannot be read anymore: {}", e),
+            ))
+        }
+    };
+    let package =
+        db::get_webhook_packages(pool.clone(), &target.repository.full_name, target.sender.id)
+            .await
+            .expect("DB error")
+            .into_iter()
+            .find(|package| package.id == p.id);
+    let result = match &package {
+        Some(package) => {
+            handle_webhook(
+                delivery.delivery.as_deref(),
+                delivery.event.as_deref(),
+                payload.as_bytes(),
+                package,
+                pool.clone(),
+            )
+            .await
+        }
+        None => Err(reject(
+            StatusCode::CONFLICT,
+            "package_mismatch",
+            format!(
+                "the delivery is not from the repository of {} or not sent by its owner",
+                path.0
+            ),
+        )),
+    };
+    log_delivery(&delivery, &result, true, pool.clone()).await;
+    if let (Ok(_), Some(id)) = (&result, &delivery.delivery) {
+        record_delivery(id, pool).await;
+    }
+
+    webhook_response(&result)
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    from: Version,
+    to: Version,
+}
+
+/// Compares two versions of a package. Fails with 404 if either of them or
+/// a needed archive is missing.
+async fn version_diff(
+    pool: web::Data<SqlitePool>,
+    name: &str,
+    query: &DiffQuery,
+) -> Result<diff::VersionDiff, HttpResponse> {
+    let versions = db::get_package_versions(pool.clone(), name, true)
+        .await
+        .expect("DB error");
+    let find = |version: &Version| versions.iter().position(|v| &v.version == version);
+    let (old, new) = match (find(&query.from), find(&query.to)) {
+        (Some(old), Some(new)) => (old, new),
+        _ => return Err(HttpResponse::NotFound().finish()),
+    };
+
+    let old_files = db::get_files(pool.clone(), name, &query.from)
+        .await
+        .expect("DB error");
+    let new_files = db::get_files(pool, name, &query.to)
+        .await
+        .expect("DB error");
+    let mut changes = diff::file_changes(&old_files, &new_files);
+
+    // Only the changed files small enough to diff are read
+    let paths = |size: fn(&diff::FileChange) -> Option<u64>| -> Vec<String> {
+        changes
+            .iter()
+            .filter(|c| c.diffable() && size(c).is_some())
+            .map(|c| c.path.clone())
+            .collect()
+    };
+    let (old_paths, new_paths) = (paths(|c| c.old_size), paths(|c| c.new_size));
+    let limit = diff::MAX_DIFF_SIZE as u64 + 1;
+
+    let mut contents = Vec::new();
+    for (version, paths) in [(&query.from, old_paths), (&query.to, new_paths)] {
+        match files::read(name.to_string(), version.clone(), paths, limit).await {
+            Ok(Some(files)) => contents.push(files),
+            Ok(None) => return Err(HttpResponse::NotFound().finish()),
+            Err(e) => {
+                error!("Failed to read {} {}: {}", name, version, e);
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+    let (new_contents, old_contents) = (contents.pop().unwrap(), contents.pop().unwrap());
+
+    let diff = web::block(move || {
+        diff::add_diffs(&mut changes, &old_contents, &new_contents);
+        diff::compare(&versions[old], &versions[new], changes)
+    })
+    .await
+    .unwrap();
+
+    Ok(diff)
+}
+
+#[get("/api/packages/{name}/diff")]
+async fn get_package_diff(
+    name: web::Path<String>,
+    query: web::Query<DiffQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    match version_diff(pool, &name, &query).await {
+        Ok(diff) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(to_string_pretty(&diff).unwrap()),
+        Err(response) => response,
+    }
+}
+
+#[get("/api/packages/{name}/{version}/signature")]
+async fn get_package_signature(
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    match db::get_signature(pool, &path.0, &path.1)
+        .await
+        .expect("DB error")
+    {
+        Some(signature) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(to_string_pretty(&signature).unwrap()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/api/packages/{name}/{version}/files")]
+async fn get_package_files(
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let (name, version) = path.into_inner();
+    let files = db::get_files(pool, &name, &version)
+        .await
+        .expect("DB error");
+
+    // Every published version has files, none are listed for unknown versions
+    if files.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_string_pretty(&files).unwrap())
+}
+
+#[post("/api/packages/{name}/{version}/yank")]
+async fn yank_version(
+    req: HttpRequest,
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    set_yanked(req, path, pool, session, true).await
+}
+
+#[post("/api/packages/{name}/{version}/unyank")]
+async fn unyank_version(
+    req: HttpRequest,
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    set_yanked(req, path, pool, session, false).await
+}
+
+async fn set_yanked(
+    req: HttpRequest,
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+    yanked: bool,
+) -> HttpResponse {
+    let id = match auth::authorize(&req, &session, &pool, Scope::Yank).await {
+        Ok(id) => id,
+        Err(res) => return res,
+    };
+
+    match db::get_package(pool.clone(), &path.0)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            if db::set_yanked(pool, p.id, &path.1, yanked)
+                .await
+                .expect("DB error")
+            {
+                HttpResponse::NoContent().finish()
+            } else {
+                HttpResponse::NotFound().finish()
+            }
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[derive(Deserialize)]
+struct ResolveQuery {
+    package: String,
+    version: Option<VersionReq>,
+    bot_type: BotType,
+    bot_version: Version,
+}
+
+#[get("/api/resolve")]
+async fn resolve_dependencies(
+    query: web::Query<ResolveQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let registry =
+        resolver::load_registry(pool, &query.package, &query.bot_type, &query.bot_version)
+            .await
+            .expect("DB error");
+    let req = query.version.clone().unwrap_or(VersionReq::STAR);
+    let bot = format!("{} {}", query.bot_type, query.bot_version);
+
+    match resolver::resolve(&registry, &query.package, &req, bot) {
+        Ok(plan) => HttpResponse::Ok()
+            .content_type("application/json")
+            .body(to_string_pretty(&plan).unwrap()),
+        Err(reason) => HttpResponse::Conflict().json(ApiError::new("unresolvable", reason)),
+    }
+}
+
+#[post("/api/updates")]
+async fn check_updates(
+    web::Json(query): web::Json<updates::UpdateQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if query.packages.len() > updates::MAX_PACKAGES {
+        return HttpResponse::BadRequest().json(ApiError::new(
+            "too_many_packages",
+            format!(
+                "at most {} packages can be checked at once",
+                updates::MAX_PACKAGES
+            ),
+        ));
+    }
+
+    let updates = updates::check_updates(pool, query).await.expect("DB error");
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_string_pretty(&updates).unwrap())
+}
+
+/// Restricts package listings to versions supporting a bot.
+#[derive(Deserialize)]
+struct CompatibilityQuery {
+    #[serde(default, deserialize_with = "empty_as_none")]
+    bot_type: Option<BotType>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    bot_version: Option<Version>,
+}
+
+impl CompatibilityQuery {
+    fn is_empty(&self) -> bool {
+        self.bot_type.is_none() && self.bot_version.is_none()
+    }
+}
+
+/// Treats empty query parameters, as sent by HTML forms, like missing ones.
+fn empty_as_none<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(value) if !value.is_empty() => {
+            T::deserialize(IntoDeserializer::<D::Error>::into_deserializer(value)).map(Some)
+        }
+        _ => Ok(None),
+    }
+}
+
+#[derive(Deserialize)]
+struct ListingQuery {
+    sort: Option<listing::Sort>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// Comma separated fields to include for each version.
+    fields: Option<String>,
+}
+
+impl ListingQuery {
+    fn is_paginated(&self) -> bool {
+        self.sort.is_some() || self.cursor.is_some() || self.limit.is_some()
+    }
+}
+
+#[get("/api/packages")]
+async fn get_all_package_data(
+    query: web::Query<CompatibilityQuery>,
+    listing_query: web::Query<ListingQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let cursor = match listing_query.cursor.as_deref().map(listing::Cursor::decode) {
+        Some(None) => {
+            return HttpResponse::BadRequest()
+                .json(ApiError::new("invalid_cursor", "the cursor is not valid"))
+        }
+        cursor => cursor.flatten(),
+    };
+    let fields: Option<Vec<&str>> = listing_query.fields.as_deref().map(|f| {
+        f.split(',')
+            .map(str::trim)
+            .filter(|f| !f.is_empty())
+            .collect()
+    });
+    let unknown_field = |field: String| {
+        HttpResponse::BadRequest().json(ApiError::new(
+            "unknown_field",
+            format!("{} is not a field of packages", field),
+        ))
+    };
+
+    if !listing_query.is_paginated() {
+        let packages = if query.is_empty() {
+            db::get_all_packages(pool).await
+        } else {
+            db::get_compatible_packages(
+                pool,
+                query.bot_type.as_ref(),
+                query.bot_version.as_ref(),
+                false,
+            )
+            .await
+        }
+        .expect("DB error");
+
+        let body = match &fields {
+            Some(fields) => listing::select_fields(&packages, fields)
+                .map(|packages| to_string_pretty(&packages).unwrap()),
+            None => Ok(to_string_pretty(&packages).unwrap()),
+        };
+
+        return match body {
+            Ok(body) => HttpResponse::Ok()
+                .content_type("application/json")
+                .body(body),
+            Err(field) => unknown_field(field),
+        };
+    }
+
+    let sort = cursor
+        .as_ref()
+        .map_or(listing_query.sort.unwrap_or_default(), |c| c.sort);
+    let limit = listing_query
+        .limit
+        .unwrap_or(listing::DEFAULT_LIMIT)
+        .clamp(1, listing::MAX_LIMIT);
+    let packages = db::get_packages_page(
+        pool,
+        sort,
+        cursor.as_ref(),
+        query.bot_type.as_ref(),
+        query.bot_version.as_ref(),
+        limit,
+    )
+    .await
+    .expect("DB error");
+    let page = listing::page(packages, sort, limit);
+
+    match &fields {
+        Some(fields) => match listing::select_fields(&page.packages, fields) {
+            Ok(packages) => HttpResponse::Ok().json(listing::Page {
+                packages,
+                next_cursor: page.next_cursor,
+            }),
+            Err(field) => unknown_field(field),
+        },
+        None => HttpResponse::Ok().json(page),
+    }
+}
+
+#[get("/api/packages/{name}/{version}/download")]
+async fn download_package(
+    req: HttpRequest,
+    path: web::Path<(String, Version)>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    if !path
+        .0
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let digest = db::get_digest(pool.clone(), &path.0, &path.1)
+        .await
+        .expect("DB error");
+    let etag = digest.as_ref().map(|d| format!("\"{}\"", d.sha256));
+
+    if let (Some(etag), Some(if_none_match)) = (&etag, req.headers().get(IF_NONE_MATCH)) {
+        if if_none_match
+            .to_str()
+            .is_ok_and(|v| v.split(',').any(|t| t.trim() == etag))
+        {
+            return HttpResponse::NotModified()
+                .insert_header((ETAG, etag.as_str()))
+                .finish();
+        }
+    }
+
+    match NamedFile::open(db::artifact_path(&path.0, &path.1)) {
+        Ok(f) => {
+            let mut res = f.use_etag(digest.is_none()).into_response(&req);
+
+            if let (Some(digest), Some(etag)) = (digest, etag) {
+                let raw = hex::decode(&digest.sha256).unwrap();
+                let headers = res.headers_mut();
+                headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+                headers.insert(
+                    HeaderName::from_static("digest"),
+                    HeaderValue::from_str(&format!("sha-256={}", STANDARD.encode(raw))).unwrap(),
+                );
+            }
+
+            if res.status() == StatusCode::OK {
+                // A lost count is better than a failed download
+                if let Err(e) = db::record_download(pool, &path.0, &path.1).await {
+                    error!("Failed to record download of {} {}: {}", path.0, path.1, e);
+                }
+            }
+
+            res
+        }
+        Err(_) => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/")]
+async fn package_list(
+    query: web::Query<CompatibilityQuery>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let packages = if query.is_empty() {
+        db::get_latest_packages(pool).await
+    } else {
+        db::get_compatible_packages(
+            pool,
+            query.bot_type.as_ref(),
+            query.bot_version.as_ref(),
+            true,
+        )
+        .await
+    }
+    .expect("DB error");
+    let logged_in = matches!(session.get::<i64>("id"), Ok(Some(_)));
+    HttpResponse::Ok().content_type("text/html").body(
+        templates::Index {
+            packages,
+            logged_in,
+            bot_type: query
+                .bot_type
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            bot_version: query
+                .bot_version
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
 }
 
-#[get("/api/packages/{name}/{version}/download")]
-async fn download_package(req: HttpRequest, path: web::Path<(String, Version)>) -> impl Responder {
-    if path
-        .0
-        .chars()
-        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
-    {
-        let path = Path::new("data").join(format!("{}-{}.zip", path.0, path.1));
+#[get("/api/search")]
+async fn search_packages(
+    query: web::Query<SearchQuery>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let results = db::search(pool, &query.q).await.expect("DB error");
 
-        match NamedFile::open(path) {
-            Ok(f) => Ok(f.into_response(&req)),
-            Err(_) => HttpResponse::NotFound().await,
-        }
-    } else {
-        HttpResponse::NotFound().await
-    }
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(to_string_pretty(&results).unwrap())
 }
 
-#[get("/")]
-async fn package_list(pool: web::Data<SqlitePool>, session: Session) -> impl Responder {
-    let packages = db::get_latest_packages(pool).await.expect("DB error");
+#[get("/search")]
+async fn search_view(
+    query: web::Query<SearchQuery>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let results = db::search(pool, &query.q).await.expect("DB error");
     let logged_in = matches!(session.get::<i64>("id"), Ok(Some(_)));
+
     HttpResponse::Ok().content_type("text/html").body(
-        templates::Index {
-            packages,
+        templates::Search {
             logged_in,
+            query: &query.q,
+            results,
         }
         .render()
         .unwrap(),
@@ -159,20 +1025,255 @@ async fn upload_view(session: Session) -> impl Responder {
         .body(templates::Upload { logged_in }.render().unwrap())
 }
 
+#[get("/tokens")]
+async fn tokens_view(pool: web::Data<SqlitePool>, session: Session) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        let tokens = db::get_tokens(pool, id).await.expect("DB error");
+
+        HttpResponse::Ok().content_type("text/html").body(
+            templates::Tokens {
+                logged_in: true,
+                tokens,
+                scopes: &Scope::ALL,
+            }
+            .render()
+            .unwrap(),
+        )
+    } else {
+        HttpResponse::Found()
+            .append_header(("Location", "/login"))
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct NewToken {
+    name: String,
+    scopes: Vec<Scope>,
+}
+
+#[derive(Serialize)]
+struct CreatedToken {
+    id: i64,
+    token: String,
+}
+
+// Tokens can only be managed with a browser session, never with another token
+#[post("/tokens")]
+async fn create_token(
+    web::Json(data): web::Json<NewToken>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        if data.name.is_empty() || data.name.len() > 50 || data.scopes.is_empty() {
+            return HttpResponse::BadRequest().json(ApiError::new(
+                "invalid_token",
+                "a token needs a name of at most 50 characters and at least one scope",
+            ));
+        }
+
+        let token = auth::generate_token();
+        let token_id = db::create_token(
+            pool,
+            id,
+            &data.name,
+            &auth::hash_token(&token),
+            &data.scopes,
+        )
+        .await
+        .expect("DB error");
+
+        HttpResponse::Created().json(CreatedToken {
+            id: token_id,
+            token,
+        })
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+#[delete("/tokens/{id}")]
+async fn revoke_token(
+    token_id: web::Path<i64>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        if db::revoke_token(pool, id, *token_id)
+            .await
+            .expect("DB error")
+        {
+            HttpResponse::NoContent().finish()
+        } else {
+            HttpResponse::NotFound().finish()
+        }
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+#[get("/keys")]
+async fn keys_view(pool: web::Data<SqlitePool>, session: Session) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        let keys = db::get_signing_keys(pool, id).await.expect("DB error");
+
+        HttpResponse::Ok().content_type("text/html").body(
+            templates::Keys {
+                logged_in: true,
+                keys,
+            }
+            .render()
+            .unwrap(),
+        )
+    } else {
+        HttpResponse::Found()
+            .append_header(("Location", "/login"))
+            .finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct NewKey {
+    name: String,
+    public_key: String,
+}
+
+#[post("/keys")]
+async fn create_key(
+    web::Json(data): web::Json<NewKey>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        if data.name.is_empty() || data.name.len() > 50 {
+            return HttpResponse::BadRequest().json(ApiError::new(
+                "invalid_key",
+                "a key needs a name of at most 50 characters",
+            ));
+        }
+
+        match signing::parse_public_key(&data.public_key) {
+            Ok(public_key) => {
+                db::create_signing_key(pool, id, &data.name, &public_key)
+                    .await
+                    .expect("DB error");
+                HttpResponse::Created().finish()
+            }
+            Err(e) => HttpResponse::BadRequest().json(ApiError::new("invalid_key", e)),
+        }
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+#[delete("/keys/{id}")]
+async fn revoke_key(
+    key_id: web::Path<i64>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    if let Ok(Some(id)) = session.get::<i64>("id") {
+        if db::revoke_signing_key(pool, id, *key_id)
+            .await
+            .expect("DB error")
+        {
+            HttpResponse::NoContent().finish()
+        } else {
+            HttpResponse::NotFound().finish()
+        }
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+/// The newest dependent version of each package depending on `name`.
+async fn used_by(pool: web::Data<SqlitePool>, name: &str) -> Vec<db::Dependent> {
+    let mut dependents = db::get_dependents(pool, name).await.expect("DB error");
+    dependents.dedup_by(|a, b| a.name == b.name);
+    dependents
+}
+
+#[get("/packages/{name}/integrations")]
+async fn show_package_integrations(
+    name: web::Path<String>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let id = match session.get::<i64>("id") {
+        Ok(Some(id)) => id,
+        _ => {
+            return HttpResponse::Found()
+                .append_header(("Location", "/login"))
+                .finish()
+        }
+    };
+
+    match db::get_package(pool.clone(), &name)
+        .await
+        .expect("DB error")
+    {
+        Some(p) if p.owner == id => {
+            let deliveries = db::get_deliveries(pool.clone(), p.id)
+                .await
+                .expect("DB error");
+            let jobs = db::get_jobs(pool, p.id).await.expect("DB error");
+
+            HttpResponse::Ok().content_type("text/html").body(
+                templates::Integrations {
+                    logged_in: true,
+                    name: name.into_inner(),
+                    deliveries,
+                    jobs,
+                    max_attempts: jobs::MAX_ATTEMPTS,
+                }
+                .render()
+                .unwrap(),
+            )
+        }
+        Some(_) => HttpResponse::Forbidden().finish(),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/packages/{name}/diff")]
+async fn show_package_diff(
+    name: web::Path<String>,
+    query: web::Query<DiffQuery>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let user_id = session.get::<i64>("id").ok().flatten();
+
+    match version_diff(pool, &name, &query).await {
+        Ok(diff) => HttpResponse::Ok().content_type("text/html").body(
+            templates::Diff {
+                logged_in: user_id.is_some(),
+                diff,
+            }
+            .render()
+            .unwrap(),
+        ),
+        Err(response) => response,
+    }
+}
+
 #[get("/packages/{name}/{version}")]
 async fn show_package_data(
     path: web::Path<(String, Version)>,
     pool: web::Data<SqlitePool>,
     session: Session,
 ) -> impl Responder {
-    let package = db::get_package_with_version(pool, &path.0, &path.1).await;
-    let logged_in = matches!(session.get::<i64>("id"), Ok(Some(_)));
+    let package = db::get_package_with_version(pool.clone(), &path.0, &path.1).await;
+    let user_id = session.get::<i64>("id").ok().flatten();
 
     match package {
         Ok(pkg) => HttpResponse::Ok().content_type("text/html").body(
             templates::PackageTemplate {
+                is_owner: user_id == Some(pkg.owner),
+                used_by: used_by(pool, &pkg.name).await,
                 package: pkg,
-                logged_in,
+                logged_in: user_id.is_some(),
             }
             .render()
             .unwrap(),
@@ -187,14 +1288,16 @@ async fn show_latest_package_data(
     pool: web::Data<SqlitePool>,
     session: Session,
 ) -> impl Responder {
-    let package = db::get_latest_package(pool, &name).await;
-    let logged_in = matches!(session.get::<i64>("id"), Ok(Some(_)));
+    let package = db::get_latest_package(pool.clone(), &name).await;
+    let user_id = session.get::<i64>("id").ok().flatten();
 
     match package {
         Ok(pkg) => HttpResponse::Ok().content_type("text/html").body(
             templates::PackageTemplate {
+                is_owner: user_id == Some(pkg.owner),
+                used_by: used_by(pool, &pkg.name).await,
                 package: pkg,
-                logged_in,
+                logged_in: user_id.is_some(),
             }
             .render()
             .unwrap(),
@@ -209,15 +1312,20 @@ async fn show_package_version_data(
     pool: web::Data<SqlitePool>,
     session: Session,
 ) -> impl Responder {
-    let packages = db::get_package_versions(pool, &name).await;
-    let logged_in = matches!(session.get::<i64>("id"), Ok(Some(_)));
+    let user_id = session.get::<i64>("id").ok().flatten();
+    let is_owner = match db::get_package(pool.clone(), &name).await {
+        Ok(Some(p)) => user_id == Some(p.owner),
+        _ => false,
+    };
+    let packages = db::get_package_versions(pool, &name, is_owner).await;
 
     match packages {
         Ok(pkgs) => HttpResponse::Ok().content_type("text/html").body(
             templates::PackagesTemplate {
                 packages: pkgs,
                 name: &name,
-                logged_in,
+                logged_in: user_id.is_some(),
+                is_owner,
             }
             .render()
             .unwrap(),
@@ -226,6 +1334,83 @@ async fn show_package_version_data(
     }
 }
 
+#[get("/packages/{name}/{version}/assets/{path:.*}")]
+async fn package_asset(
+    path: web::Path<(String, Version, String)>,
+    pool: web::Data<SqlitePool>,
+) -> impl Responder {
+    let (name, version, asset_path) = path.into_inner();
+
+    match db::get_asset(pool, &name, &version, &asset_path)
+        .await
+        .expect("DB error")
+    {
+        Some(asset) => HttpResponse::Ok()
+            .content_type(asset.content_type)
+            .insert_header((CACHE_CONTROL, "public, max-age=31536000, immutable"))
+            .insert_header((X_CONTENT_TYPE_OPTIONS, "nosniff"))
+            .insert_header((CONTENT_SECURITY_POLICY, "default-src 'none'; sandbox"))
+            .body(asset.data),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/packages/{name}/{version}/files")]
+async fn show_package_files_root(path: web::Path<(String, Version)>) -> impl Responder {
+    HttpResponse::Found()
+        .append_header((
+            "Location",
+            format!("/packages/{}/{}/files/", path.0, path.1),
+        ))
+        .finish()
+}
+
+#[get("/packages/{name}/{version}/files/{path:.*}")]
+async fn show_package_files(
+    path: web::Path<(String, Version, String)>,
+    pool: web::Data<SqlitePool>,
+    session: Session,
+) -> impl Responder {
+    let (name, version, file_path) = path.into_inner();
+    let user_id = session.get::<i64>("id").ok().flatten();
+    let package = match db::get_package_with_version(pool.clone(), &name, &version).await {
+        Ok(pkg) => pkg,
+        Err(_) => return HttpResponse::NotFound().finish(),
+    };
+    let files = db::get_files(pool, &name, &version)
+        .await
+        .expect("DB error");
+
+    let content = match files.iter().find(|f| f.path == file_path) {
+        Some(entry) => match files::view(name.clone(), version.clone(), entry.clone()).await {
+            Ok(Some(file)) => templates::FilesContent::File(file),
+            Ok(None) => return HttpResponse::NotFound().finish(),
+            Err(e) => {
+                error!(
+                    "Failed to read {} of {} {}: {}",
+                    file_path, name, version, e
+                );
+                return HttpResponse::InternalServerError().finish();
+            }
+        },
+        None => match files::directory(&files, &file_path) {
+            Some(entries) => templates::FilesContent::Directory(entries),
+            None => return HttpResponse::NotFound().finish(),
+        },
+    };
+
+    HttpResponse::Ok().content_type("text/html").body(
+        templates::Files {
+            logged_in: user_id.is_some(),
+            package,
+            breadcrumbs: files::breadcrumbs(&file_path),
+            content,
+        }
+        .render()
+        .unwrap(),
+    )
+}
+
 #[get("/login")]
 async fn login() -> impl Responder {
     HttpResponse::Found()
@@ -248,50 +1433,327 @@ async fn redirected_back(
         .finish())
 }
 
+/// A rejected webhook delivery: the response status and the error.
+type Rejection = (StatusCode, ApiError);
+
+fn reject(status: StatusCode, error: &'static str, message: impl ToString) -> Rejection {
+    (status, ApiError::new(error, message))
+}
+
+/// Checks the signature of a webhook request. The packages it can be for are
+/// those of the sender published from the repository, and each of them only
+/// accepts its own secret, or the registry-wide one if it has none. Returns
+/// the packages whose secret signed the request, the payload is only trusted
+/// for those. A rejection comes with the packages the request claims to be
+/// for, if any, so their owners can see why it failed.
+async fn verify_webhook(
+    req: &HttpRequest,
+    body: &[u8],
+    pool: web::Data<SqlitePool>,
+) -> Result<Vec<db::WebhookPackage>, (Rejection, Vec<db::WebhookPackage>)> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    if header(webhook::DELIVERY_HEADER).is_none() {
+        return Err((
+            reject(
+                StatusCode::BAD_REQUEST,
+                "missing_delivery",
+                format!("the {} header is missing", webhook::DELIVERY_HEADER),
+            ),
+            Vec::new(),
+        ));
+    }
+    let target: webhook::WebhookTarget = serde_json::from_slice(body).map_err(|e| {
+        (
+            reject(StatusCode::BAD_REQUEST, "invalid_payload", e),
+            Vec::new(),
+        )
+    })?;
+
+    let packages = db::get_webhook_packages(pool, &target.repository.full_name, target.sender.id)
+        .await
+        .expect("DB error");
+    if packages.is_empty() {
+        return Err((
+            reject(
+                StatusCode::NOT_FOUND,
+                "package_not_found",
+                "the sender has no package published from this repository",
+            ),
+            packages,
+        ));
+    }
+    let signature = match header(webhook::SIGNATURE_HEADER) {
+        Some(signature) => signature,
+        None => {
+            return Err((
+                reject(
+                    StatusCode::UNAUTHORIZED,
+                    "missing_signature",
+                    "the webhook is not signed, configure a secret for it",
+                ),
+                packages,
+            ))
+        }
+    };
+
+    let secret = |package: &db::WebhookPackage| {
+        package
+            .webhook_secret
+            .clone()
+            .or_else(|| webhook::GLOBAL_SECRET.clone())
+    };
+    if !packages.iter().any(|package| secret(package).is_some()) {
+        return Err((
+            reject(
+                StatusCode::FORBIDDEN,
+                "webhook_not_configured",
+                "no webhook secret is configured for the packages of this repository",
+            ),
+            packages,
+        ));
+    }
+
+    let (verified, unverified): (Vec<_>, Vec<_>) = packages.into_iter().partition(|package| {
+        secret(package).is_some_and(|secret| {
+            webhook::verify_signature(body, signature, std::slice::from_ref(&secret))
+        })
+    });
+    if verified.is_empty() {
+        return Err((
+            reject(
+                StatusCode::UNAUTHORIZED,
+                "invalid_signature",
+                "the webhook signature does not match",
+            ),
+            unverified,
+        ));
+    }
+
+    Ok(verified)
+}
+
+#[derive(Serialize)]
+struct QueuedImport {
+    job: i64,
+}
+
+fn webhook_response(result: &Result<webhook::Outcome, Rejection>) -> HttpResponse {
+    match result {
+        Ok(webhook::Outcome::Queued(job)) => {
+            HttpResponse::Accepted().json(QueuedImport { job: *job })
+        }
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err((status, error)) => HttpResponse::build(*status).json(error),
+    }
+}
+
+/// Adds a delivery and its result to the delivery log shown to the package
+/// owner. The response does not depend on it, so failures are only logged.
+async fn log_delivery(
+    delivery: &db::WebhookDelivery,
+    result: &Result<webhook::Outcome, Rejection>,
+    redelivery: bool,
+    pool: web::Data<SqlitePool>,
+) {
+    let (outcome, error) = match result {
+        Ok(outcome) => (outcome.to_string(), None),
+        Err((_, error)) => (error.error.to_string(), Some(error.message.as_str())),
+    };
+    let job = result.as_ref().ok().and_then(webhook::Outcome::job);
+    let status = webhook_response(result).status().as_u16();
+
+    if let Err(e) = db::log_delivery(pool, delivery, status, &outcome, error, job, redelivery).await
+    {
+        error!("Could not log webhook delivery: {}", e);
+    }
+}
+
+/// Remembers a handled delivery ID. Like the delivery log, this must not turn
+/// a handled delivery into an error response, so failures are only logged.
+async fn record_delivery(id: &str, pool: web::Data<SqlitePool>) {
+    if let Err(e) = db::record_delivery(pool, id).await {
+        error!("Could not record webhook delivery {}: {}", id, e);
+    }
+}
+
 #[post("/webhook")]
 async fn github_webhook(
-    web::Json(data): web::Json<webhook::GithubReleaseWebhook>,
-    client: web::Data<Client>,
+    req: HttpRequest,
+    body: web::Bytes,
     pool: web::Data<SqlitePool>,
 ) -> impl Responder {
-    if data.action != "published" {
-        debug!("{:?}: Not a publish release event, ignoring.", data);
-        return HttpResponse::NoContent().finish();
-    }
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let delivery = header(webhook::DELIVERY_HEADER);
+    let event = header(webhook::EVENT_HEADER);
+
+    let target = serde_json::from_slice::<webhook::WebhookTarget>(&body).ok();
+    let logged = |package: &db::WebhookPackage, payload| {
+        target.as_ref().map(|target| db::WebhookDelivery {
+            package: package.id,
+            delivery: delivery.map(String::from),
+            event: event.map(String::from),
+            repository: target.repository.full_name.clone(),
+            action: target.action.clone(),
+            payload,
+        })
+    };
 
-    let db_pkg = db::get_package_by_repo(pool.clone(), data.repository.full_name, data.sender.id)
+    let packages = match verify_webhook(&req, &body, pool.clone()).await {
+        Ok(packages) => packages,
+        Err((rejection, packages)) => {
+            let result = Err(rejection);
+            // Owners see why the delivery failed, but an unverified payload
+            // is not kept, so it cannot be redelivered
+            for package in &packages {
+                if let Some(logged) = logged(package, None) {
+                    log_delivery(&logged, &result, false, pool.clone()).await;
+                }
+            }
+            return webhook_response(&result);
+        }
+    };
+    // Verification made sure the delivery ID is there
+    let id = delivery.unwrap();
+    let replayed = db::is_delivery_recorded(pool.clone(), id)
         .await
         .expect("DB error");
 
-    if let Some(p) = db_pkg {
-        if let Ok(payload) = webhook::get_latest_release(&p.github.unwrap(), client).await {
-            let cur = Cursor::new(payload.clone());
-
-            match package::try_parse(cur).await {
-                Ok(pkg) => {
-                    if !db::validate_data(&pkg) {
-                        return HttpResponse::BadRequest()
-                            .body("Package format OK, but parts too long");
-                    }
-
-                    return match db::create_package(pool, pkg, p.owner, payload).await {
-                        Ok(_) => HttpResponse::Created().finish(),
-                        Err(_) => HttpResponse::Forbidden().finish(),
-                    };
-                }
-                Err(e) => {
-                    debug!("Error parsing package: {:?}", e);
-                    return HttpResponse::BadRequest().body(format!("{:?}", e));
-                }
-            };
+    let mut results = Vec::new();
+    for package in &packages {
+        let result = if replayed {
+            Err(reject(
+                StatusCode::CONFLICT,
+                "replayed_delivery",
+                format!("delivery {} was already handled", id),
+            ))
         } else {
-            return HttpResponse::NotFound().body("no release found");
+            handle_webhook(delivery, event, &body, package, pool.clone()).await
+        };
+
+        if let Some(logged) = logged(package, std::str::from_utf8(&body).ok().map(String::from)) {
+            log_delivery(&logged, &result, false, pool.clone()).await;
+        }
+        results.push(result);
+    }
+
+    // Failed deliveries can be redelivered by GitHub and are handled again
+    if results.iter().all(Result::is_ok) {
+        record_delivery(id, pool).await;
+    }
+
+    // The first rejection, if any, tells GitHub that something went wrong
+    let result = results.iter().find(|r| r.is_err()).unwrap_or(&results[0]);
+    webhook_response(result)
+}
+
+/// Acts on a verified webhook delivery for `package`.
+async fn handle_webhook(
+    delivery: Option<&str>,
+    event: Option<&str>,
+    body: &[u8],
+    package: &db::WebhookPackage,
+    pool: web::Data<SqlitePool>,
+) -> Result<webhook::Outcome, Rejection> {
+    if event != Some("release") {
+        debug!("{:?}: Not a release event, ignoring.", event);
+        return Ok(webhook::Outcome::Ignored);
+    }
+
+    let data: webhook::GithubReleaseWebhook = serde_json::from_slice(body)
+        .map_err(|e| reject(StatusCode::BAD_REQUEST, "invalid_payload", e))?;
+
+    match data.release_action() {
+        webhook::ReleaseAction::Ignore => {
+            debug!("{:?}: Not a release action we handle, ignoring.", data);
+            Ok(webhook::Outcome::Ignored)
+        }
+        webhook::ReleaseAction::Publish => {
+            let repository = data.repository.full_name;
+            enqueue_release(data.release, delivery, &repository, package, pool).await
         }
+        action => update_release(data.release, action, package, pool).await,
+    }
+}
+
+/// Queues the import of a GitHub release, see [`jobs::run`].
+async fn enqueue_release(
+    release: webhook::Release,
+    delivery: Option<&str>,
+    repository: &str,
+    package: &db::WebhookPackage,
+    pool: web::Data<SqlitePool>,
+) -> Result<webhook::Outcome, Rejection> {
+    let id = db::enqueue_job(
+        pool,
+        package.id,
+        delivery,
+        repository,
+        &release.tag_name,
+        &serde_json::to_string(&release).unwrap(),
+    )
+    .await
+    .expect("DB error");
+
+    Ok(webhook::Outcome::Queued(id))
+}
+
+/// Applies a change to a GitHub release to the version published from it.
+async fn update_release(
+    release: webhook::Release,
+    action: webhook::ReleaseAction,
+    package: &db::WebhookPackage,
+    pool: web::Data<SqlitePool>,
+) -> Result<webhook::Outcome, Rejection> {
+    let version_not_found = || {
+        reject(
+            StatusCode::NOT_FOUND,
+            "version_not_found",
+            format!(
+                "no version of {} was published from {}",
+                package.name, release.tag_name
+            ),
+        )
+    };
+    let version = release.version().ok_or_else(version_not_found)?;
+
+    let (found, outcome) = match action {
+        webhook::ReleaseAction::Yank => (
+            db::set_yanked(pool, package.id, &version, true).await,
+            webhook::Outcome::Yanked,
+        ),
+        _ => match release.notes() {
+            Some(notes) if notes.len() > db::MAX_RELEASE_NOTES => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    ApiError::from(vec![db::Violation::MaxLength {
+                        field: "release_notes",
+                        limit: db::MAX_RELEASE_NOTES,
+                        actual: notes.len(),
+                    }]),
+                ))
+            }
+            Some(notes) => (
+                db::set_release_notes(pool, package.id, &version, &notes).await,
+                webhook::Outcome::NotesUpdated,
+            ),
+            // An empty description keeps the notes from the CHANGELOG.md
+            None => return Ok(webhook::Outcome::Ignored),
+        },
+    };
+
+    if found.expect("DB error") {
+        Ok(outcome)
     } else {
-        return HttpResponse::NotFound().body("no package found");
+        Err(version_not_found())
     }
 }
 
+fn http_client() -> Client {
+    Client::builder()
+        .wrap(awc::middleware::Redirect::new())
+        .finish()
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::dotenv().ok();
@@ -300,11 +1762,8 @@ async fn main() -> std::io::Result<()> {
     }
     env_logger::init();
 
-    let conn_options = SqliteConnectOptions::from_str(&var("DATABASE_URL").unwrap())
-        .unwrap()
-        .collation("semver_collation", |a, b| {
-            Version::parse(a).unwrap().cmp(&Version::parse(b).unwrap())
-        });
+    let conn_options =
+        db::with_collations(SqliteConnectOptions::from_str(&var("DATABASE_URL").unwrap()).unwrap());
 
     let pool = SqlitePoolOptions::new()
         .connect_with(conn_options)
@@ -316,16 +1775,31 @@ async fn main() -> std::io::Result<()> {
         .await
         .expect("Migration failed");
 
+    if let Some(command) = args().nth(1) {
+        let ok = admin::run(&command, &pool).await;
+        exit(if ok { 0 } else { 1 });
+    }
+
+    db::clean_staging()
+        .await
+        .expect("Could not prepare staging directory");
+    admin::index_missing(&pool).await;
+    admin::list_missing_files(&pool).await;
+    admin::find_orphans(&pool).await;
+
     let key = Key::derive_from(
         var("COOKIE_SECRET")
             .expect("COOKIE_SECRET is not set")
             .as_bytes(),
     );
 
+    db::requeue_running_jobs(&pool)
+        .await
+        .expect("Could not requeue interrupted jobs");
+    actix_web::rt::spawn(jobs::run(Data::new(http_client()), Data::new(pool.clone())));
+
     HttpServer::new(move || {
-        let client = Client::builder()
-            .wrap(awc::middleware::Redirect::new())
-            .finish();
+        let client = http_client();
 
         App::new()
             .app_data(Data::new(pool.clone()))
@@ -338,17 +1812,46 @@ async fn main() -> std::io::Result<()> {
             ))
             .service(Files::new("/assets", "./static"))
             .service(upload_package)
+            .service(validate_package)
             .service(download_package)
+            .service(get_package_dependents)
+            .service(get_package_stats)
+            .service(get_package_diff)
+            .service(get_package_jobs)
+            .service(get_package_deliveries)
             .service(get_package_data)
+            .service(get_package_signature)
+            .service(get_package_files)
             .service(get_package_versions)
             .service(get_all_package_data)
+            .service(resolve_dependencies)
+            .service(check_updates)
+            .service(yank_version)
+            .service(unyank_version)
+            .service(create_webhook_secret)
+            .service(delete_webhook_secret)
+            .service(retry_package_job)
+            .service(redeliver_package_delivery)
+            .service(search_packages)
             .service(package_list)
+            .service(search_view)
             .service(faq)
             .service(api)
             .service(upload_view)
+            .service(tokens_view)
+            .service(create_token)
+            .service(revoke_token)
+            .service(keys_view)
+            .service(create_key)
+            .service(revoke_key)
             .service(show_latest_package_data)
+            .service(show_package_diff)
+            .service(show_package_integrations)
             .service(show_package_data)
             .service(show_package_version_data)
+            .service(package_asset)
+            .service(show_package_files_root)
+            .service(show_package_files)
             .service(login)
             .service(redirected_back)
             .service(github_webhook)
@@ -357,3 +1860,89 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[actix_web::test]
+async fn test_update_release() {
+    let pool = db::test_pool().await;
+    sqlx::query(
+        r#"INSERT INTO packages ("id", "owner", "name") VALUES (1, 12345678, 'EXPORT_MODULE'), (2, 12345678, 'EXPORT_EXTRA'), (3, 999, 'CLAIMED'), (4, 12345678, 'MOVED');
+        INSERT INTO versions ("package", "description", "short_description", "version", "author", "bot_type", "bot_version", "github")
+            VALUES (1, '', '', '1.2.0', 'Me', 'Nadybot', '^5.0.0', 'Nadybot/EXPORT_MODULE'), (2, '', '', '1.2.0', 'Me', 'Nadybot', '^5.0.0', 'Nadybot/EXPORT_MODULE'),
+            (3, '', '', '1.2.0', 'Me', 'Nadybot', '^5.0.0', 'Nadybot/EXPORT_MODULE'), (4, '', '', '1.0.0', 'Me', 'Nadybot', '^5.0.0', 'Nadybot/EXPORT_MODULE'),
+            (4, '', '', '1.1.0', 'Me', 'Nadybot', '^5.0.0', 'Nadybot/MOVED');"#,
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    let pool = Data::new(pool);
+
+    // Neither packages of others nor those moved to another repository match
+    let packages = db::get_webhook_packages(pool.clone(), "Nadybot/EXPORT_MODULE", 12345678)
+        .await
+        .unwrap();
+    let names: Vec<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, vec!["EXPORT_EXTRA", "EXPORT_MODULE"]);
+
+    let deliver = |fixture: &'static str, package| {
+        handle_webhook(
+            None,
+            Some("release"),
+            fixture.as_bytes(),
+            package,
+            pool.clone(),
+        )
+    };
+    let state = || async {
+        let rows: Vec<(bool, Option<String>)> = sqlx::query_as(
+            r#"SELECT "yanked", "release_notes" FROM versions WHERE "package" IN (1, 2) ORDER BY "package";"#,
+        )
+        .fetch_all(&**pool)
+        .await
+        .unwrap();
+        rows
+    };
+
+    for package in &packages {
+        assert_eq!(
+            deliver(
+                include_str!("../fixtures/webhooks/release_edited.json"),
+                package
+            )
+            .await
+            .ok(),
+            Some(webhook::Outcome::NotesUpdated)
+        );
+    }
+    let notes = Some(String::from(
+        "## Added\n\n- Export of raid points\n- Import of raid points",
+    ));
+    assert_eq!(
+        state().await,
+        vec![(false, notes.clone()), (false, notes.clone())]
+    );
+
+    for package in &packages {
+        assert_eq!(
+            deliver(
+                include_str!("../fixtures/webhooks/release_unpublished.json"),
+                package
+            )
+            .await
+            .ok(),
+            Some(webhook::Outcome::Yanked)
+        );
+    }
+    assert_eq!(state().await, vec![(true, notes.clone()), (true, notes)]);
+
+    let moved = db::WebhookPackage {
+        id: 4,
+        name: String::from("MOVED"),
+        webhook_secret: None,
+    };
+    let result = deliver(
+        include_str!("../fixtures/webhooks/release_deleted.json"),
+        &moved,
+    )
+    .await;
+    assert_eq!(result.unwrap_err().0, StatusCode::NOT_FOUND);
+}
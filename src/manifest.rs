@@ -52,8 +52,15 @@ pub struct PackageManifest {
     pub bot_type: BotType,
     pub bot_version: VersionReq,
     pub github: Option<String>,
+    /// Legacy mix of package dependencies and platform requirements.
     #[serde(default)]
     pub requires: HashMap<String, VersionReq>,
+    /// Other aopkg packages this package depends on.
+    #[serde(default)]
+    pub dependencies: HashMap<String, VersionReq>,
+    /// Requirements on the bot's runtime, like the PHP version or extensions.
+    #[serde(default)]
+    pub platform: HashMap<String, VersionReq>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -74,6 +81,58 @@ pub struct PackageManifestDb {
     pub bot_version: VersionReq,
     pub github: Option<String>,
     pub requires: Vec<Requirement>,
+    pub dependencies: Vec<Requirement>,
+    pub platform: Vec<Requirement>,
+    pub yanked: bool,
+    pub sha256: Option<String>,
+    pub size: Option<i64>,
+    pub signing_key: Option<i64>,
+    pub published_at: Option<String>,
+    pub downloads: i64,
+    /// Downloads in the last 30 days.
+    pub recent_downloads: i64,
+    /// Notes on what changed in this version, as Markdown.
+    pub release_notes: Option<String>,
+}
+
+#[cfg(test)]
+impl PackageManifestDb {
+    /// A Nadybot package version for any bot version, all other fields empty.
+    pub fn test(name: &str, version: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: String::new(),
+            short_description: String::new(),
+            version: Version::parse(version).unwrap(),
+            author: String::new(),
+            owner: 1,
+            bot_type: BotType::Nadybot,
+            bot_version: VersionReq::STAR,
+            github: None,
+            requires: Vec::new(),
+            dependencies: Vec::new(),
+            platform: Vec::new(),
+            yanked: false,
+            sha256: None,
+            size: None,
+            signing_key: None,
+            published_at: None,
+            downloads: 0,
+            recent_downloads: 0,
+            release_notes: None,
+        }
+    }
+}
+
+/// Parses requirements stored as a JSON object, sorted by name.
+fn parse_requirements(input: &str) -> Vec<Requirement> {
+    let map: HashMap<String, VersionReq> = serde_json::from_str(input).unwrap();
+    let mut requirements: Vec<Requirement> = map
+        .into_iter()
+        .map(|(name, version)| Requirement { name, version })
+        .collect();
+    requirements.sort_by(|a, b| a.name.cmp(&b.name));
+    requirements
 }
 
 #[derive(FromRow, Decode)]
@@ -88,6 +147,7 @@ where
     &'s str: ColumnIndex<R>,
     String: Type<R::Database> + Decode<'r, R::Database>,
     i64: Type<R::Database> + Decode<'r, R::Database>,
+    bool: Type<R::Database> + Decode<'r, R::Database>,
 {
     #[inline]
     fn from_row(row: &'r R) -> Result<Self, SqlxError> {
@@ -100,13 +160,17 @@ where
         let bot_version: String = row.try_get("bot_version")?;
         let github: Option<String> = row.try_get("github")?;
         let owner: i64 = row.try_get("owner")?;
+        let yanked: bool = row.try_get("yanked")?;
+        let sha256: Option<String> = row.try_get("sha256")?;
+        let size: Option<i64> = row.try_get("size")?;
+        let signing_key: Option<i64> = row.try_get("signing_key")?;
+        let published_at: Option<String> = row.try_get("published_at")?;
+        let downloads: i64 = row.try_get("downloads")?;
+        let recent_downloads: i64 = row.try_get("recent_downloads")?;
+        let release_notes: Option<String> = row.try_get("release_notes")?;
         let requires_str: String = row.try_get("requires")?;
-        let requires_map: HashMap<String, VersionReq> =
-            serde_json::from_str(&requires_str).unwrap();
-        let requires = requires_map
-            .into_iter()
-            .map(|(name, version)| Requirement { name, version })
-            .collect();
+        let dependencies_str: String = row.try_get("dependencies")?;
+        let platform_str: String = row.try_get("platform")?;
 
         Ok(Self {
             name,
@@ -118,7 +182,17 @@ where
             bot_type: BotType::try_from(bot_type).unwrap(),
             bot_version: VersionReq::parse(&bot_version).unwrap(),
             github,
-            requires,
+            requires: parse_requirements(&requires_str),
+            dependencies: parse_requirements(&dependencies_str),
+            platform: parse_requirements(&platform_str),
+            yanked,
+            sha256,
+            size,
+            signing_key,
+            published_at,
+            downloads,
+            recent_downloads,
+            release_notes,
         })
     }
 }
@@ -154,6 +228,35 @@ fn test_loads_valid() {
         bot_version: VersionReq::parse("^5.0.0").unwrap(), // Op is not exposed, cannot hardcode
         github: None,
         requires: HashMap::new(),
+        dependencies: HashMap::new(),
+        platform: HashMap::new(),
     };
     assert_eq!(load_package_manifest(input).unwrap(), expected);
 }
+
+#[test]
+fn test_loads_requirement_sections() {
+    let input = r#"
+    name = "EXPORT_MODULE"
+    description = "Exports stuff"
+    version = "1.0.0"
+    author = "Nadyita <nadyita@hodorraid.org>"
+    bot_type = "Nadybot"
+    bot_version = "^5.0.0"
+
+    [dependencies]
+    BASE_MODULE = "^1.2"
+
+    [platform]
+    php = ">=8.0"
+    ext-openssl = "*"
+    "#;
+    let manifest = load_package_manifest(input).unwrap();
+
+    assert!(manifest.requires.is_empty());
+    assert_eq!(
+        manifest.dependencies.get("BASE_MODULE"),
+        Some(&VersionReq::parse("^1.2").unwrap())
+    );
+    assert_eq!(manifest.platform.len(), 2);
+}
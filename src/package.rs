@@ -1,10 +1,14 @@
 // Validates and parses a zip file.
 use crate::{
-    description::to_html,
+    changelog,
+    description::{self, Links},
+    files::{self, FileEntry},
     manifest::{load_package_manifest, PackageManifest},
+    signing::{decode_signature, ARCHIVE_SIGNATURE_FILE},
 };
 
 use actix_web::web::Bytes;
+use ed25519_dalek::Signature;
 use serde::Serialize;
 use tokio::{
     task::spawn_blocking,
@@ -13,21 +17,86 @@ use tokio::{
 use toml::de::Error;
 use zip::{read::ZipFile, result::ZipError, ZipArchive};
 
-use std::io::{Cursor, Error as IOError, Read, Seek};
+use std::{
+    collections::BTreeSet,
+    fmt::{Display, Formatter, Result as FmtResult},
+    io::{Cursor, Error as IOError, Read, Seek},
+};
+
+/// Largest file that is served as a README asset.
+const MAX_ASSET_SIZE: u64 = 1024 * 1024;
+/// Largest combined size of the README assets of a package.
+const MAX_ASSETS_SIZE: u64 = 5 * 1024 * 1024;
+const MAX_ASSETS: usize = 20;
 
 #[derive(Debug, Serialize)]
 pub struct Package {
     pub manifest: PackageManifest,
     pub description: String,
+    #[serde(skip)]
+    pub archive_signature: Option<ArchiveSignature>,
+    #[serde(skip)]
+    pub assets: Vec<Asset>,
+    /// All files in the archive, sorted by path.
+    #[serde(skip)]
+    pub files: Vec<FileEntry>,
+    /// Notes on what changed in this version, as Markdown.
+    pub release_notes: Option<String>,
+}
+
+/// An image referenced by the README and bundled in the package.
+#[derive(Debug)]
+pub struct Asset {
+    /// Path relative to the package root.
+    pub path: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// A signature included in the archive as `aopkg.sig`.
+///
+/// It signs the listing of all other files in the archive in `sha256sum`
+/// format, sorted by path and relative to the package root.
+#[derive(Debug)]
+pub struct ArchiveSignature {
+    pub signature: Signature,
+    pub listing: String,
 }
 
 #[derive(Debug)]
 pub enum ParseError {
     ZipError(ZipError),
-    TOMLError(Error),
+    MissingFile(&'static str),
+    InvalidSignature,
+    TOMLError {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
     Timeout,
 }
 
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::ZipError(e) => write!(f, "invalid ZIP file: {}", e),
+            Self::MissingFile(name) => write!(f, "{} is missing from the package", name),
+            Self::InvalidSignature => write!(
+                f,
+                "{} does not contain a base64 encoded ed25519 signature",
+                ARCHIVE_SIGNATURE_FILE
+            ),
+            Self::TOMLError {
+                message,
+                line: Some(line),
+                column: Some(column),
+            } => write!(f, "invalid aopkg.toml at {}:{}: {}", line, column, message),
+            Self::TOMLError { message, .. } => write!(f, "invalid aopkg.toml: {}", message),
+            Self::Timeout => write!(f, "parsing the package took too long"),
+        }
+    }
+}
+
 pub type ParseResult<T> = Result<T, ParseError>;
 
 impl From<ZipError> for ParseError {
@@ -42,9 +111,24 @@ impl From<IOError> for ParseError {
     }
 }
 
-impl From<Error> for ParseError {
-    fn from(e: Error) -> Self {
-        Self::TOMLError(e)
+impl ParseError {
+    /// Converts a TOML error, resolving its span to a 1-based line and column in `input`.
+    fn from_toml(e: Error, input: &str) -> Self {
+        let (line, column) = match e.span() {
+            Some(span) => {
+                let before = &input[..span.start.min(input.len())];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+
+        Self::TOMLError {
+            message: e.message().to_string(),
+            line,
+            column,
+        }
     }
 }
 
@@ -60,34 +144,144 @@ fn read_file(mut file: ZipFile) -> ParseResult<String> {
     Ok(buffer)
 }
 
-fn parse(reader: impl Read + Seek) -> ParseResult<Package> {
-    let mut zip = ZipArchive::new(reader)?;
+fn find_file<'a, R: Read + Seek>(
+    zip: &'a mut ZipArchive<R>,
+    prepend: &str,
+    name: &'static str,
+) -> ParseResult<ZipFile<'a>> {
+    match zip.by_name(&format!("{}{}", prepend, name)) {
+        Ok(file) => Ok(file),
+        Err(ZipError::FileNotFound) => Err(ParseError::MissingFile(name)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn content_listing(files: &[FileEntry]) -> String {
+    files
+        .iter()
+        .filter(|file| file.path != ARCHIVE_SIGNATURE_FILE)
+        .map(|file| format!("{}  {}\n", file.sha256, file.path))
+        .collect()
+}
+
+/// The content type of files that can be served as README assets.
+fn asset_type(path: &str) -> Option<&'static str> {
+    let extension = path.rsplit_once('.')?.1.to_ascii_lowercase();
+    match extension.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+/// Reads the images among `paths` from the archive. Files of other types,
+/// missing files and files exceeding the size limits are skipped.
+fn extract_assets<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    prepend: &str,
+    paths: &BTreeSet<String>,
+) -> ParseResult<Vec<Asset>> {
+    let mut assets = Vec::new();
+    let mut total = 0;
+
+    for path in paths {
+        let content_type = match asset_type(path) {
+            Some(content_type) => content_type,
+            None => continue,
+        };
+        let file = match zip.by_name(&format!("{}{}", prepend, path)) {
+            Ok(file) => file,
+            Err(ZipError::FileNotFound) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if file.size() > MAX_ASSET_SIZE || total + file.size() > MAX_ASSETS_SIZE {
+            continue;
+        }
+
+        let mut data = Vec::new();
+        file.take(MAX_ASSET_SIZE).read_to_end(&mut data)?;
+        total += data.len() as u64;
+        assets.push(Asset {
+            path: path.clone(),
+            content_type,
+            data,
+        });
 
+        if assets.len() == MAX_ASSETS {
+            break;
+        }
+    }
+
+    Ok(assets)
+}
+
+/// The directory all files of the archive are in, like `EXPORT_MODULE/`, or
+/// an empty string if they are at the top level.
+pub fn package_root<R: Read + Seek>(zip: &ZipArchive<R>) -> String {
     let names: Vec<&str> = zip.file_names().collect();
-    let mut prepend = String::new();
     for name in names.clone() {
         if names.iter().all(|i| i.starts_with(name) || *i == name) {
-            prepend = name.to_string();
-            break;
+            return name.to_string();
         }
     }
+    String::new()
+}
+
+fn parse(reader: impl Read + Seek) -> ParseResult<Package> {
+    let mut zip = ZipArchive::new(reader)?;
+    let prepend = package_root(&zip);
+    let files = files::entries(&mut zip, &prepend)?;
 
     let readme_md = {
-        let readme = zip.by_name(&format!("{}README.md", prepend))?;
+        let readme = find_file(&mut zip, &prepend, "README.md")?;
         read_file(readme)?
     };
 
     let manifest_str = {
-        let manifest = zip.by_name(&format!("{}aopkg.toml", prepend))?;
+        let manifest = find_file(&mut zip, &prepend, "aopkg.toml")?;
         read_file(manifest)?
     };
 
-    let manifest = load_package_manifest(&manifest_str)?;
-    let description = to_html(&readme_md);
+    let manifest = load_package_manifest(&manifest_str)
+        .map_err(|e| ParseError::from_toml(e, &manifest_str))?;
+
+    let assets = extract_assets(&mut zip, &prepend, &description::relative_paths(&readme_md))?;
+    let links = Links {
+        assets_url: format!("/packages/{}/{}/assets/", manifest.name, manifest.version),
+        bundled: assets.iter().map(|a| a.path.clone()).collect(),
+        github: manifest.github.clone(),
+    };
+    let description = description::to_html(&readme_md, Some(links));
+
+    let release_notes = match zip.by_name(&format!("{}CHANGELOG.md", prepend)) {
+        Ok(file) => changelog::release_notes(&read_file(file)?, &manifest.version),
+        Err(ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    let archive_signature = match zip.by_name(&format!("{}{}", prepend, ARCHIVE_SIGNATURE_FILE)) {
+        Ok(mut file) => {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents)?;
+            Some(decode_signature(&contents).ok_or(ParseError::InvalidSignature)?)
+        }
+        Err(ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+    let archive_signature = archive_signature.map(|signature| ArchiveSignature {
+        signature,
+        listing: content_listing(&files),
+    });
 
     Ok(Package {
         manifest,
         description,
+        archive_signature,
+        assets,
+        files,
+        release_notes,
     })
 }
 
@@ -95,3 +289,17 @@ pub async fn try_parse(reader: Cursor<Bytes>) -> ParseResult<Package> {
     let task = spawn_blocking(move || parse(reader));
     timeout(Duration::from_secs(5), task).await?.unwrap()
 }
+
+#[test]
+fn test_toml_error_location() {
+    let input = "name = \"EXPORT_MODULE\"\nversion = \"1.0\"\n";
+    let e = load_package_manifest(input).unwrap_err();
+
+    match ParseError::from_toml(e, input) {
+        ParseError::TOMLError { line, column, .. } => {
+            assert_eq!(line, Some(2));
+            assert_eq!(column, Some(11));
+        }
+        e => panic!("unexpected error: {:?}", e),
+    }
+}
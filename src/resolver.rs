@@ -0,0 +1,410 @@
+// Resolves the transitive requirements of a package into an install plan
+use crate::{
+    db,
+    manifest::{BotType, PackageManifestDb},
+};
+
+use actix_web::web::Data;
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use sqlx::{Error, SqlitePool};
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+/// Upper bound of versions tried before giving up.
+const MAX_STEPS: usize = 100_000;
+
+pub struct Candidate {
+    pub version: Version,
+    pub dependencies: HashMap<String, VersionReq>,
+    pub platform: HashMap<String, VersionReq>,
+    /// Whether this version supports the bot the plan is computed for.
+    pub compatible: bool,
+}
+
+/// All known versions per package, newest first.
+pub type Registry = HashMap<String, Vec<Candidate>>;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PlannedPackage {
+    pub name: String,
+    pub version: Version,
+}
+
+/// A platform requirement of a planned package, e.g. a PHP extension.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PlatformRequirement {
+    pub name: String,
+    pub version: VersionReq,
+    pub required_by: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InstallPlan {
+    /// Packages to install, dependencies before the packages requiring them.
+    pub packages: Vec<PlannedPackage>,
+    pub platform: Vec<PlatformRequirement>,
+}
+
+#[derive(Clone)]
+struct Constraint {
+    name: String,
+    req: VersionReq,
+    required_by: Option<(String, Version)>,
+}
+
+impl Constraint {
+    fn describe(&self) -> String {
+        match &self.required_by {
+            Some((name, version)) => format!("{} (required by {} {})", self.req, name, version),
+            None => format!("{} (requested)", self.req),
+        }
+    }
+}
+
+struct Resolver<'a> {
+    registry: &'a Registry,
+    bot: String,
+    steps: usize,
+    failure: Option<String>,
+}
+
+impl<'a> Resolver<'a> {
+    fn fail(&mut self, name: &str, constraints: &[&Constraint]) {
+        let versions = &self.registry[name];
+        let reqs: Vec<String> = constraints.iter().map(|c| c.describe()).collect();
+
+        let reason = if !versions.iter().any(|c| c.compatible) {
+            format!("no published version of {} supports {}", name, self.bot)
+        } else {
+            format!(
+                "no version of {} compatible with {} matches {}",
+                name,
+                self.bot,
+                reqs.join(" and ")
+            )
+        };
+        self.failure = Some(reason);
+    }
+
+    fn solve(
+        &mut self,
+        selected: &mut BTreeMap<String, Version>,
+        constraints: &mut Vec<Constraint>,
+    ) -> bool {
+        let name = match constraints.iter().find(|c| !selected.contains_key(&c.name)) {
+            Some(c) if !self.registry.contains_key(&c.name) => {
+                self.failure = Some(format!(
+                    "{} is not a published package, but is required as {}",
+                    c.name,
+                    c.describe()
+                ));
+                return false;
+            }
+            Some(c) => c.name.clone(),
+            None => return true,
+        };
+
+        let relevant: Vec<&Constraint> = constraints.iter().filter(|c| c.name == name).collect();
+        let candidates: Vec<&Candidate> = self.registry[&name]
+            .iter()
+            .filter(|c| c.compatible && relevant.iter().all(|r| r.req.matches(&c.version)))
+            .collect();
+
+        if candidates.is_empty() {
+            self.fail(&name, &relevant);
+            return false;
+        }
+
+        for candidate in candidates {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                self.failure = Some(String::from("the requirements are too complex to resolve"));
+                return false;
+            }
+
+            let new: Vec<Constraint> = candidate
+                .dependencies
+                .iter()
+                .map(|(dep, req)| Constraint {
+                    name: dep.clone(),
+                    req: req.clone(),
+                    required_by: Some((name.clone(), candidate.version.clone())),
+                })
+                .collect();
+
+            // Requirements on packages we already picked have to hold as well
+            if let Some(clash) = new
+                .iter()
+                .find(|c| selected.get(&c.name).is_some_and(|v| !c.req.matches(v)))
+            {
+                self.failure = Some(format!(
+                    "{} {} is selected, but {} {} requires {}",
+                    clash.name, selected[&clash.name], name, candidate.version, clash.req
+                ));
+                continue;
+            }
+
+            let len = constraints.len();
+            selected.insert(name.clone(), candidate.version.clone());
+            constraints.extend(new);
+
+            if self.solve(selected, constraints) {
+                return true;
+            }
+
+            constraints.truncate(len);
+            selected.remove(&name);
+
+            if self.steps > MAX_STEPS {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+fn order_plan(
+    registry: &Registry,
+    selected: &BTreeMap<String, Version>,
+    name: &str,
+    visited: &mut HashSet<String>,
+    plan: &mut Vec<PlannedPackage>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+
+    let version = &selected[name];
+    let candidate = registry[name]
+        .iter()
+        .find(|c| &c.version == version)
+        .unwrap();
+
+    let mut deps: Vec<&String> = candidate.dependencies.keys().collect();
+    deps.sort();
+    for dep in deps {
+        order_plan(registry, selected, dep, visited, plan);
+    }
+
+    plan.push(PlannedPackage {
+        name: name.to_string(),
+        version: version.clone(),
+    });
+}
+
+/// Picks one version per package so that all transitive dependencies of
+/// `name` matching `req` hold. Newer versions are preferred. Returns an
+/// explanation of the conflict if there is no such set.
+pub fn resolve(
+    registry: &Registry,
+    name: &str,
+    req: &VersionReq,
+    bot: String,
+) -> Result<InstallPlan, String> {
+    if !registry.contains_key(name) {
+        return Err(format!("{} is not a known package", name));
+    }
+
+    let mut resolver = Resolver {
+        registry,
+        bot,
+        steps: 0,
+        failure: None,
+    };
+    let mut selected = BTreeMap::new();
+    let mut constraints = vec![Constraint {
+        name: name.to_string(),
+        req: req.clone(),
+        required_by: None,
+    }];
+
+    if !resolver.solve(&mut selected, &mut constraints) {
+        return Err(resolver
+            .failure
+            .unwrap_or_else(|| String::from("the requirements cannot be satisfied")));
+    }
+
+    let mut packages = Vec::new();
+    order_plan(
+        registry,
+        &selected,
+        name,
+        &mut HashSet::new(),
+        &mut packages,
+    );
+
+    let platform = packages
+        .iter()
+        .flat_map(|p| {
+            let candidate = registry[&p.name]
+                .iter()
+                .find(|c| c.version == p.version)
+                .unwrap();
+            let mut reqs: Vec<PlatformRequirement> = candidate
+                .platform
+                .iter()
+                .map(|(name, req)| PlatformRequirement {
+                    name: name.clone(),
+                    version: req.clone(),
+                    required_by: p.name.clone(),
+                })
+                .collect();
+            reqs.sort_by(|a, b| a.name.cmp(&b.name));
+            reqs
+        })
+        .collect();
+
+    Ok(InstallPlan { packages, platform })
+}
+
+/// Loads every package reachable through `dependencies` from `name` with all
+/// their non-yanked versions.
+pub async fn load_registry(
+    pool: Data<SqlitePool>,
+    name: &str,
+    bot_type: &BotType,
+    bot_version: &Version,
+) -> Result<Registry, Error> {
+    let mut registry = Registry::new();
+    let mut queue = VecDeque::from(vec![name.to_string()]);
+    let mut seen = HashSet::new();
+
+    while let Some(name) = queue.pop_front() {
+        if !seen.insert(name.clone()) {
+            continue;
+        }
+
+        let versions: Vec<PackageManifestDb> =
+            db::get_package_versions(pool.clone(), &name, false).await?;
+        if versions.is_empty() {
+            continue;
+        }
+
+        let candidates = versions
+            .into_iter()
+            .map(|v| {
+                let dependencies: HashMap<String, VersionReq> = v
+                    .dependencies
+                    .into_iter()
+                    .map(|r| (r.name, r.version))
+                    .collect();
+                queue.extend(dependencies.keys().cloned());
+
+                Candidate {
+                    compatible: &v.bot_type == bot_type && v.bot_version.matches(bot_version),
+                    version: v.version,
+                    dependencies,
+                    platform: v
+                        .platform
+                        .into_iter()
+                        .map(|r| (r.name, r.version))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        registry.insert(name, candidates);
+    }
+
+    Ok(registry)
+}
+
+#[cfg(test)]
+fn candidate(version: &str, dependencies: &[(&str, &str)]) -> Candidate {
+    Candidate {
+        version: Version::parse(version).unwrap(),
+        dependencies: dependencies
+            .iter()
+            .map(|(n, r)| (n.to_string(), VersionReq::parse(r).unwrap()))
+            .collect(),
+        platform: HashMap::new(),
+        compatible: true,
+    }
+}
+
+#[test]
+fn test_resolve_backtracks() {
+    let mut registry = Registry::new();
+    registry.insert(
+        String::from("APP"),
+        vec![candidate("1.0.0", &[("LIB", "^1"), ("UTIL", "*")])],
+    );
+    registry.insert(
+        String::from("UTIL"),
+        vec![
+            candidate("2.0.0", &[("LIB", "^2")]),
+            Candidate {
+                platform: vec![(String::from("ext-openssl"), VersionReq::STAR)]
+                    .into_iter()
+                    .collect(),
+                ..candidate("1.0.0", &[("LIB", "^1")])
+            },
+        ],
+    );
+    registry.insert(
+        String::from("LIB"),
+        vec![candidate("2.0.0", &[]), candidate("1.4.0", &[])],
+    );
+
+    let plan = resolve(
+        &registry,
+        "APP",
+        &VersionReq::STAR,
+        String::from("Nadybot 5.0.0"),
+    )
+    .unwrap();
+
+    assert_eq!(
+        plan.packages,
+        vec![
+            PlannedPackage {
+                name: String::from("LIB"),
+                version: Version::new(1, 4, 0),
+            },
+            PlannedPackage {
+                name: String::from("UTIL"),
+                version: Version::new(1, 0, 0),
+            },
+            PlannedPackage {
+                name: String::from("APP"),
+                version: Version::new(1, 0, 0),
+            },
+        ]
+    );
+    assert_eq!(
+        plan.platform,
+        vec![PlatformRequirement {
+            name: String::from("ext-openssl"),
+            version: VersionReq::STAR,
+            required_by: String::from("UTIL"),
+        }]
+    );
+}
+
+#[test]
+fn test_resolve_explains_conflicts() {
+    let mut registry = Registry::new();
+    registry.insert(
+        String::from("APP"),
+        vec![candidate("1.0.0", &[("LIB", "^3")])],
+    );
+    registry.insert(
+        String::from("LIB"),
+        vec![candidate("2.0.0", &[]), candidate("1.4.0", &[])],
+    );
+
+    let err = resolve(
+        &registry,
+        "APP",
+        &VersionReq::STAR,
+        String::from("Nadybot 5.0.0"),
+    )
+    .unwrap_err();
+
+    assert_eq!(
+        err,
+        "no version of LIB compatible with Nadybot 5.0.0 matches ^3 (required by APP 1.0.0)"
+    );
+}
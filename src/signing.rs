@@ -0,0 +1,107 @@
+// Verification of author signatures on packages
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey, PUBLIC_KEY_LENGTH};
+use serde::Serialize;
+use sqlx::FromRow;
+
+use std::convert::TryInto;
+
+/// DER prefix of an ed25519 SubjectPublicKeyInfo, as written by `openssl pkey -pubout`.
+const SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Name of the signature file that may be included in a package archive.
+pub const ARCHIVE_SIGNATURE_FILE: &str = "aopkg.sig";
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureKind {
+    /// Signs the uploaded ZIP file as a whole.
+    Detached,
+    /// Signs the checksum listing of all files in the archive.
+    Archive,
+}
+
+impl SignatureKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Detached => "detached",
+            Self::Archive => "archive",
+        }
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct SigningKeyDb {
+    pub id: i64,
+    pub owner: i64,
+    pub name: String,
+    pub public_key: String,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+}
+
+/// Parses an ed25519 public key. Accepts the base64 of the raw 32 bytes or a
+/// PEM encoded SubjectPublicKeyInfo. Returns the key as base64 of the raw bytes.
+pub fn parse_public_key(input: &str) -> Result<String, &'static str> {
+    let input = input.trim();
+    let b64: String = if input.starts_with("-----BEGIN PUBLIC KEY-----") {
+        input.lines().filter(|l| !l.starts_with("-----")).collect()
+    } else {
+        input.to_string()
+    };
+
+    let bytes = STANDARD
+        .decode(b64.trim())
+        .map_err(|_| "Key is not valid base64")?;
+    let raw = match bytes.strip_prefix(&SPKI_PREFIX) {
+        Some(raw) => raw,
+        None => &bytes,
+    };
+    let raw: [u8; PUBLIC_KEY_LENGTH] = raw.try_into().map_err(|_| "Key has the wrong length")?;
+
+    VerifyingKey::from_bytes(&raw).map_err(|_| "Key is not a valid ed25519 key")?;
+
+    Ok(STANDARD.encode(raw))
+}
+
+/// Decodes a base64 signature, as sent in a header or stored in an archive.
+pub fn decode_signature(input: &[u8]) -> Option<Signature> {
+    let text = std::str::from_utf8(input).ok()?;
+    let bytes = STANDARD.decode(text.trim()).ok()?;
+    Signature::from_slice(&bytes).ok()
+}
+
+/// Checks `signature` over `message` against a key as stored by [`parse_public_key`].
+pub fn verify(public_key: &str, message: &[u8], signature: &Signature) -> bool {
+    let bytes = match STANDARD.decode(public_key) {
+        Ok(b) => b,
+        Err(_) => return false,
+    };
+
+    match bytes.as_slice().try_into().map(VerifyingKey::from_bytes) {
+        Ok(Ok(key)) => key.verify(message, signature).is_ok(),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_verifies_signatures() {
+    use ed25519_dalek::{Signer, SigningKey};
+
+    let signing_key = SigningKey::from_bytes(&[7; 32]);
+    let public_key = STANDARD.encode(signing_key.verifying_key().as_bytes());
+    let pem = format!(
+        "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+        STANDARD.encode([&SPKI_PREFIX[..], signing_key.verifying_key().as_bytes()].concat())
+    );
+    assert_eq!(parse_public_key(&pem).unwrap(), public_key);
+
+    let signature = signing_key.sign(b"package contents");
+    let encoded = STANDARD.encode(signature.to_bytes());
+    let decoded = decode_signature(encoded.as_bytes()).unwrap();
+
+    assert!(verify(&public_key, b"package contents", &decoded));
+    assert!(!verify(&public_key, b"other contents", &decoded));
+}
@@ -0,0 +1,119 @@
+// Download statistics of packages
+use crate::db::{self, DailyDownloads};
+
+use actix_web::web::Data;
+use serde::Serialize;
+use sqlx::{Error, SqlitePool};
+
+use std::collections::BTreeMap;
+
+pub const DEFAULT_DAYS: u32 = 30;
+pub const MAX_DAYS: u32 = 365;
+
+#[derive(Serialize)]
+pub struct VersionStats {
+    pub version: String,
+    pub downloads: i64,
+    pub recent_downloads: i64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DayStats {
+    pub day: String,
+    pub downloads: i64,
+    /// Downloads per version on this day, versions without any are left out.
+    pub versions: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize)]
+pub struct PackageStats {
+    pub name: String,
+    pub downloads: i64,
+    /// Downloads in the last 30 days.
+    pub recent_downloads: i64,
+    pub versions: Vec<VersionStats>,
+    /// Days with downloads, oldest first.
+    pub days: Vec<DayStats>,
+}
+
+/// Groups per-version download counts by day. `daily` has to be sorted by day.
+fn group_by_day(daily: Vec<DailyDownloads>) -> Vec<DayStats> {
+    let mut days: Vec<DayStats> = Vec::new();
+
+    for row in daily {
+        match days.last_mut() {
+            Some(day) if day.day == row.day => {
+                day.downloads += row.count;
+                day.versions.insert(row.version, row.count);
+            }
+            _ => days.push(DayStats {
+                day: row.day,
+                downloads: row.count,
+                versions: vec![(row.version, row.count)].into_iter().collect(),
+            }),
+        }
+    }
+
+    days
+}
+
+/// Loads the download statistics of a package for the last `days` days.
+/// Returns `None` for unknown packages.
+pub async fn load(
+    pool: Data<SqlitePool>,
+    name: &str,
+    days: u32,
+) -> Result<Option<PackageStats>, Error> {
+    let versions = db::get_package_versions(pool.clone(), name, true).await?;
+    if versions.is_empty() {
+        return Ok(None);
+    }
+    let daily = db::get_daily_downloads(pool, name, days).await?;
+
+    Ok(Some(PackageStats {
+        name: name.to_string(),
+        downloads: versions.iter().map(|v| v.downloads).sum(),
+        recent_downloads: versions.iter().map(|v| v.recent_downloads).sum(),
+        versions: versions
+            .into_iter()
+            .map(|v| VersionStats {
+                version: v.version.to_string(),
+                downloads: v.downloads,
+                recent_downloads: v.recent_downloads,
+            })
+            .collect(),
+        days: group_by_day(daily),
+    }))
+}
+
+#[test]
+fn test_group_by_day() {
+    let row = |day: &str, version: &str, count| DailyDownloads {
+        day: day.to_string(),
+        version: version.to_string(),
+        count,
+    };
+    let days = group_by_day(vec![
+        row("2026-10-17", "1.0.0", 2),
+        row("2026-10-17", "1.1.0", 5),
+        row("2026-10-18", "1.1.0", 1),
+    ]);
+
+    assert_eq!(
+        days,
+        vec![
+            DayStats {
+                day: String::from("2026-10-17"),
+                downloads: 7,
+                versions: vec![(String::from("1.0.0"), 2), (String::from("1.1.0"), 5)]
+                    .into_iter()
+                    .collect(),
+            },
+            DayStats {
+                day: String::from("2026-10-18"),
+                downloads: 1,
+                versions: vec![(String::from("1.1.0"), 1)].into_iter().collect(),
+            },
+        ]
+    );
+}
@@ -1,18 +1,37 @@
-use crate::manifest::PackageManifestDb;
+use crate::{
+    auth::{Scope, TokenDb},
+    db::{Delivery, Dependent, SearchResult},
+    description,
+    diff::{FileStatus, VersionDiff},
+    files::{DirEntry, FileView},
+    jobs::{Job, JobStatus},
+    manifest::PackageManifestDb,
+    signing::SigningKeyDb,
+};
 
 use askama::Template;
+use serde_json::Value;
 
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct Index {
     pub logged_in: bool,
     pub packages: Vec<PackageManifestDb>,
+    pub bot_type: String,
+    pub bot_version: String,
+}
+
+impl Index {
+    fn is_bot_type(&self, bot_type: &str) -> bool {
+        self.bot_type == bot_type
+    }
 }
 
 #[derive(Template)]
 #[template(path = "packages.html")]
 pub struct PackagesTemplate<'a> {
     pub logged_in: bool,
+    pub is_owner: bool,
     pub name: &'a str,
     pub packages: Vec<PackageManifestDb>,
 }
@@ -21,7 +40,73 @@ pub struct PackagesTemplate<'a> {
 #[template(path = "package.html")]
 pub struct PackageTemplate {
     pub logged_in: bool,
+    pub is_owner: bool,
     pub package: PackageManifestDb,
+    pub used_by: Vec<Dependent>,
+}
+
+impl PackageTemplate {
+    fn release_notes(&self) -> Option<String> {
+        self.package
+            .release_notes
+            .as_deref()
+            .map(|notes| description::to_html(notes, None))
+    }
+}
+
+pub enum FilesContent {
+    Directory(Vec<DirEntry>),
+    File(FileView),
+}
+
+#[derive(Template)]
+#[template(path = "files.html")]
+pub struct Files {
+    pub logged_in: bool,
+    pub package: PackageManifestDb,
+    pub breadcrumbs: Vec<(String, String)>,
+    pub content: FilesContent,
+}
+
+#[derive(Template)]
+#[template(path = "diff.html")]
+pub struct Diff {
+    pub logged_in: bool,
+    pub diff: VersionDiff,
+}
+
+impl Diff {
+    fn show(&self, value: &Value) -> String {
+        match value {
+            Value::Null => String::from("none"),
+            Value::String(s) => s.clone(),
+            value => value.to_string(),
+        }
+    }
+
+    /// The CSS class of a line of a unified diff, `index` starts at 1. The
+    /// first two lines are the file headers.
+    fn line_class(&self, index: &usize, line: &str) -> &'static str {
+        if *index <= 2 {
+            "diff-header"
+        } else if line.starts_with("@@") {
+            "diff-hunk"
+        } else if line.starts_with('+') {
+            "diff-add"
+        } else if line.starts_with('-') {
+            "diff-remove"
+        } else {
+            ""
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "search.html")]
+pub struct Search<'a> {
+    pub logged_in: bool,
+    pub query: &'a str,
+    pub results: Vec<SearchResult>,
 }
 
 #[derive(Template)]
@@ -41,3 +126,46 @@ pub struct Api {
 pub struct Upload {
     pub logged_in: bool,
 }
+
+#[derive(Template)]
+#[template(path = "tokens.html")]
+pub struct Tokens<'a> {
+    pub logged_in: bool,
+    pub tokens: Vec<TokenDb>,
+    pub scopes: &'a [Scope],
+}
+
+#[derive(Template)]
+#[template(path = "keys.html")]
+pub struct Keys {
+    pub logged_in: bool,
+    pub keys: Vec<SigningKeyDb>,
+}
+
+#[derive(Template)]
+#[template(path = "integrations.html")]
+pub struct Integrations {
+    pub logged_in: bool,
+    pub name: String,
+    pub deliveries: Vec<Delivery>,
+    pub jobs: Vec<Job>,
+    pub max_attempts: i64,
+}
+
+impl Integrations {
+    fn response_badge(&self, status: &i64) -> &'static str {
+        match status {
+            200..=299 => "bg-success",
+            _ => "bg-danger",
+        }
+    }
+
+    fn badge(&self, status: &JobStatus) -> &'static str {
+        match status {
+            JobStatus::Queued => "bg-secondary",
+            JobStatus::Running => "bg-primary",
+            JobStatus::Succeeded => "bg-success",
+            JobStatus::Dead => "bg-danger",
+        }
+    }
+}
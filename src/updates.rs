@@ -0,0 +1,235 @@
+// Update checks for the packages installed on a bot
+use crate::{
+    db,
+    manifest::{BotType, PackageManifestDb},
+};
+
+use actix_web::web::Data;
+use semver::{Op, Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use sqlx::{Error, SqlitePool};
+
+/// Upper bound of packages checked in a single request.
+pub const MAX_PACKAGES: usize = 500;
+
+#[derive(Deserialize)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: Version,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateQuery {
+    pub bot_type: BotType,
+    pub bot_version: Version,
+    pub packages: Vec<InstalledPackage>,
+}
+
+/// A version that is newer than the newest compatible one, but only supports
+/// bot versions newer than the one the check was made for.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IncompatibleVersion {
+    pub version: Version,
+    pub bot_version: VersionReq,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ReleaseNotes {
+    pub version: Version,
+    /// Markdown, as published with the version.
+    pub notes: String,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct PackageUpdate {
+    pub name: String,
+    pub installed: Version,
+    /// Whether the package is published on this registry at all.
+    pub found: bool,
+    /// Whether the installed version has been yanked.
+    pub yanked: bool,
+    /// The newest non-yanked version supporting the bot.
+    pub latest: Option<Version>,
+    pub update_available: bool,
+    pub requires_newer_bot: Vec<IncompatibleVersion>,
+    /// Notes of the versions after the installed one up to `latest`, newest first.
+    pub release_notes: Vec<ReleaseNotes>,
+}
+
+/// Whether `req` only allows versions newer than `bot_version`, so that
+/// updating the bot makes it match.
+fn requires_newer(req: &VersionReq, bot_version: &Version) -> bool {
+    req.comparators.iter().any(|c| {
+        let lower = Version {
+            pre: c.pre.clone(),
+            ..Version::new(c.major, c.minor.unwrap_or(0), c.patch.unwrap_or(0))
+        };
+        match c.op {
+            Op::Greater => match (c.minor, c.patch) {
+                (Some(_), Some(_)) => bot_version <= &lower,
+                (Some(minor), None) => bot_version < &Version::new(c.major, minor + 1, 0),
+                _ => bot_version < &Version::new(c.major + 1, 0, 0),
+            },
+            Op::Exact | Op::GreaterEq | Op::Tilde | Op::Caret | Op::Wildcard => {
+                bot_version < &lower
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Compares an installed version against all `versions` of its package, newest first.
+pub fn check_package(
+    installed: InstalledPackage,
+    versions: &[PackageManifestDb],
+    bot_type: &BotType,
+    bot_version: &Version,
+) -> PackageUpdate {
+    let yanked = versions
+        .iter()
+        .any(|v| v.version == installed.version && v.yanked);
+    let available: Vec<&PackageManifestDb> = versions
+        .iter()
+        .filter(|v| !v.yanked && &v.bot_type == bot_type)
+        .collect();
+
+    let latest = available
+        .iter()
+        .find(|v| v.bot_version.matches(bot_version))
+        .map(|v| v.version.clone());
+    let newest_usable = match &latest {
+        Some(latest) if latest > &installed.version => latest,
+        _ => &installed.version,
+    };
+
+    let requires_newer_bot = available
+        .iter()
+        .filter(|v| &v.version > newest_usable && requires_newer(&v.bot_version, bot_version))
+        .map(|v| IncompatibleVersion {
+            version: v.version.clone(),
+            bot_version: v.bot_version.clone(),
+        })
+        .collect();
+
+    let release_notes = available
+        .iter()
+        .filter(|v| v.version > installed.version && Some(&v.version) <= latest.as_ref())
+        .filter_map(|v| {
+            Some(ReleaseNotes {
+                version: v.version.clone(),
+                notes: v.release_notes.clone()?,
+            })
+        })
+        .collect();
+
+    PackageUpdate {
+        update_available: latest.as_ref().is_some_and(|l| l > &installed.version),
+        name: installed.name,
+        installed: installed.version,
+        found: !versions.is_empty(),
+        yanked,
+        latest,
+        requires_newer_bot,
+        release_notes,
+    }
+}
+
+/// Checks every installed package for updates, in the order they were given.
+pub async fn check_updates(
+    pool: Data<SqlitePool>,
+    query: UpdateQuery,
+) -> Result<Vec<PackageUpdate>, Error> {
+    let mut updates = Vec::with_capacity(query.packages.len());
+
+    for installed in query.packages {
+        let versions = db::get_package_versions(pool.clone(), &installed.name, true).await?;
+        updates.push(check_package(
+            installed,
+            &versions,
+            &query.bot_type,
+            &query.bot_version,
+        ));
+    }
+
+    Ok(updates)
+}
+
+#[cfg(test)]
+fn release(version: &str, bot_version: &str, yanked: bool) -> PackageManifestDb {
+    PackageManifestDb {
+        bot_version: VersionReq::parse(bot_version).unwrap(),
+        yanked,
+        release_notes: Some(format!("Changes in {}", version)),
+        ..PackageManifestDb::test("EXPORT_MODULE", version)
+    }
+}
+
+#[test]
+fn test_check_package() {
+    let versions = vec![
+        release("2.0.0", "^6.0.0", false),
+        release("1.3.0", "~5.2.0", false),
+        release("1.2.0", "^5.0.0", true),
+        release("1.1.0", "^5.0.0", false),
+        release("1.0.0", "^5.0.0", false),
+    ];
+    let installed = InstalledPackage {
+        name: String::from("EXPORT_MODULE"),
+        version: Version::new(1, 2, 0),
+    };
+
+    let outdated = check_package(
+        InstalledPackage {
+            name: String::from("EXPORT_MODULE"),
+            version: Version::new(1, 0, 0),
+        },
+        &versions,
+        &BotType::Nadybot,
+        &Version::new(5, 4, 0),
+    );
+    assert!(outdated.update_available);
+    assert_eq!(
+        outdated.release_notes,
+        vec![ReleaseNotes {
+            version: Version::new(1, 1, 0),
+            notes: String::from("Changes in 1.1.0"),
+        }]
+    );
+
+    assert_eq!(
+        check_package(
+            installed,
+            &versions,
+            &BotType::Nadybot,
+            &Version::new(5, 4, 0)
+        ),
+        PackageUpdate {
+            name: String::from("EXPORT_MODULE"),
+            installed: Version::new(1, 2, 0),
+            found: true,
+            yanked: true,
+            latest: Some(Version::new(1, 1, 0)),
+            update_available: false,
+            requires_newer_bot: vec![IncompatibleVersion {
+                version: Version::new(2, 0, 0),
+                bot_version: VersionReq::parse("^6.0.0").unwrap(),
+            }],
+            release_notes: Vec::new(),
+        }
+    );
+}
+
+#[test]
+fn test_requires_newer() {
+    let bot_version = Version::new(5, 4, 0);
+    let requires = |req| requires_newer(&VersionReq::parse(req).unwrap(), &bot_version);
+
+    assert!(requires("^6.0.0"));
+    assert!(requires(">=5.5"));
+    assert!(requires(">5.4"));
+    assert!(requires(">=5.0.0, <6.0.0, >5.4.0"));
+    assert!(!requires("~5.2.0"));
+    assert!(!requires("<5.0.0"));
+    assert!(!requires(">5.3"));
+    assert!(!requires("*"));
+}
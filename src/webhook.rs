@@ -4,23 +4,108 @@ use actix_web::{
     Error,
 };
 use awc::Client;
+use hmac::{Hmac, Mac};
+use lazy_static::lazy_static;
 use log::debug;
-use serde::Deserialize;
+use rand::{rngs::OsRng, RngCore};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
-#[derive(Deserialize, Debug)]
+use std::{
+    env::var,
+    fmt::{Display, Formatter, Result as FmtResult},
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+pub const DELIVERY_HEADER: &str = "X-GitHub-Delivery";
+pub const EVENT_HEADER: &str = "X-GitHub-Event";
+
+lazy_static! {
+    /// Secret for the webhooks of packages without their own.
+    pub static ref GLOBAL_SECRET: Option<String> =
+        var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty());
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Asset {
     pub name: String,
-    pub url: String,
     pub browser_download_url: String,
     pub content_type: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Release {
-    pub zipball_url: String,
+    pub tag_name: String,
+    /// Missing for drafts.
+    pub zipball_url: Option<String>,
+    /// The release description, used as release notes.
+    pub body: Option<String>,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub draft: bool,
     pub assets: Vec<Asset>,
 }
 
+impl Release {
+    /// The first ZIP asset, or the source archive of the tag if there is none.
+    /// Only downloads of releases of `repository` on GitHub are accepted.
+    pub fn zip_url(&self, repository: &str) -> Option<&str> {
+        let asset = self
+            .assets
+            .iter()
+            .find(|a| a.content_type == "application/zip" || a.name.ends_with(".zip"));
+        let (url, prefix) = match asset {
+            Some(asset) => (
+                asset.browser_download_url.as_str(),
+                format!("https://github.com/{}/releases/download/", repository),
+            ),
+            None => (
+                self.zipball_url.as_deref()?,
+                format!("https://api.github.com/repos/{}/zipball/", repository),
+            ),
+        };
+
+        Some(url).filter(|url| url.starts_with(&prefix) && !url.contains(".."))
+    }
+
+    /// The version the tag names, with or without a leading `v`.
+    pub fn version(&self) -> Option<Version> {
+        Version::parse(self.tag_name.trim_start_matches(['v', 'V'])).ok()
+    }
+
+    pub fn notes(&self) -> Option<String> {
+        self.body
+            .as_deref()
+            .map(|body| body.replace("\r\n", "\n").trim().to_string())
+            .filter(|body| !body.is_empty())
+    }
+
+    /// Checks that the tag names the version of the package and that GitHub
+    /// marks the release as a prerelease exactly if the version is one.
+    pub fn check_version(&self, version: &Version) -> Result<(), String> {
+        if self.version().as_ref() != Some(version) {
+            return Err(format!(
+                "the release tag {} does not match the package version {}",
+                self.tag_name, version
+            ));
+        }
+
+        match (self.prerelease, version.pre.is_empty()) {
+            (true, true) => Err(format!(
+                "the release is marked as a prerelease, but {} is not a prerelease version",
+                version
+            )),
+            (false, false) => Err(format!(
+                "{} is a prerelease version, but the release is not marked as a prerelease",
+                version
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Sender {
     pub id: i64,
@@ -31,52 +116,216 @@ pub struct Repository {
     pub full_name: String,
 }
 
+/// The part of a webhook payload needed to find the packages it can be for
+/// and to log the delivery.
+#[derive(Deserialize, Debug)]
+pub struct WebhookTarget {
+    pub repository: Repository,
+    pub sender: Sender,
+    /// The action of the event, missing for events like `ping`.
+    pub action: Option<String>,
+}
+
+/// What the registry did with an accepted webhook delivery.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Not an event or action the registry handles.
+    Ignored,
+    /// The import of the release was queued as the given job.
+    Queued(i64),
+    Yanked,
+    NotesUpdated,
+}
+
+impl Outcome {
+    /// The job the delivery queued, if any.
+    pub fn job(&self) -> Option<i64> {
+        match self {
+            Self::Queued(job) => Some(*job),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Ignored => write!(f, "ignored"),
+            Self::Queued(_) => write!(f, "queued"),
+            Self::Yanked => write!(f, "yanked"),
+            Self::NotesUpdated => write!(f, "notes_updated"),
+        }
+    }
+}
+
+/// What a release webhook asks the registry to do.
+#[derive(Debug, PartialEq)]
+pub enum ReleaseAction {
+    /// Publish the release as a new version.
+    Publish,
+    /// Yank the version, as its release was deleted or turned back into a draft.
+    Yank,
+    /// Replace the release notes of the version with the release description.
+    UpdateNotes,
+    Ignore,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct GithubReleaseWebhook {
     pub action: String,
     pub release: Release,
     pub repository: Repository,
-    pub sender: Sender,
 }
 
-pub async fn get_latest_release(repo: &str, client: Data<Client>) -> Result<Bytes, Error> {
-    let data: Vec<Release> = client
-        .get(&format!("https://api.github.com/repos/{}/releases", repo))
-        .insert_header(("Accept", "application/vnd.github.v3+json"))
+pub fn generate_secret() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Checks a `sha256=<hex>` signature header of `body` against each of `secrets`.
+pub fn verify_signature(body: &[u8], signature: &str, secrets: &[String]) -> bool {
+    let expected = match signature
+        .strip_prefix("sha256=")
+        .and_then(|hex| hex::decode(hex).ok())
+    {
+        Some(expected) => expected,
+        None => return false,
+    };
+
+    secrets.iter().any(|secret| {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        mac.verify_slice(&expected).is_ok()
+    })
+}
+
+impl GithubReleaseWebhook {
+    pub fn release_action(&self) -> ReleaseAction {
+        match self.action.as_str() {
+            "published" => ReleaseAction::Publish,
+            "unpublished" => ReleaseAction::Yank,
+            // Drafts were never published, so there is nothing to change
+            "deleted" if !self.release.draft => ReleaseAction::Yank,
+            "edited" if !self.release.draft => ReleaseAction::UpdateNotes,
+            _ => ReleaseAction::Ignore,
+        }
+    }
+}
+
+/// Downloads the package ZIP of a release, see [`Release::zip_url`].
+pub async fn download_release(url: &str, client: Data<Client>) -> Result<Bytes, Error> {
+    debug!("Getting webhook zip from {}", url);
+
+    let bytes = client
+        .get(url)
         .insert_header(("User-Agent", "aopkg"))
         .send()
         .await
         .map_err(ErrorInternalServerError)?
-        .json()
-        .await
-        .map_err(ErrorInternalServerError)?;
+        .body()
+        .limit(15728640)
+        .await?;
+
+    Ok(bytes)
+}
 
-    debug!("Found releases for  {}: {:?}", repo, data);
+#[test]
+fn test_verify_signature() {
+    // The example from GitHub's documentation
+    let secrets = vec![
+        String::from("other"),
+        String::from("It's a Secret to Everybody"),
+    ];
+    let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
 
-    if let Some(release) = data.first() {
-        let asset = release
-            .assets
-            .iter()
-            .find(|a| a.content_type == "application/zip" || a.name.ends_with(".zip"));
-        let url = if let Some(asset) = asset {
-            &asset.browser_download_url
-        } else {
-            &release.zipball_url
-        };
-        debug!("Getting webhook zip from {}", url);
-
-        let bytes = client
-            .get(url)
-            .insert_header(("User-Agent", "aopkg"))
-            .send()
-            .await
-            .map_err(ErrorInternalServerError)?
-            .body()
-            .limit(15728640)
-            .await?;
-
-        Ok(bytes)
-    } else {
-        Err(ErrorInternalServerError("could not find release").into())
-    }
+    assert!(verify_signature(b"Hello, World!", signature, &secrets));
+    assert!(!verify_signature(b"Hello, World?", signature, &secrets));
+    assert!(!verify_signature(
+        b"Hello, World!",
+        signature,
+        &secrets[..1]
+    ));
+    assert!(!verify_signature(
+        b"Hello, World!",
+        "sha1=757107ea",
+        &secrets
+    ));
+}
+
+#[test]
+fn test_check_version() {
+    let release = |tag_name: &str, prerelease| Release {
+        tag_name: tag_name.to_string(),
+        zipball_url: None,
+        body: None,
+        prerelease,
+        draft: false,
+        assets: Vec::new(),
+    };
+    let stable = Version::new(1, 2, 0);
+    let beta = Version::parse("1.2.0-beta.1").unwrap();
+
+    assert!(release("v1.2.0", false).check_version(&stable).is_ok());
+    assert!(release("1.2.0", false).check_version(&stable).is_ok());
+    assert!(release("v1.2.0-beta.1", true).check_version(&beta).is_ok());
+    assert!(release("v1.1.0", false).check_version(&stable).is_err());
+    assert!(release("latest", false).check_version(&stable).is_err());
+    assert!(release("v1.2.0", true).check_version(&stable).is_err());
+    assert!(release("v1.2.0-beta.1", false)
+        .check_version(&beta)
+        .is_err());
+}
+
+#[test]
+fn test_release_actions() {
+    let load = |fixture: &str| -> GithubReleaseWebhook { serde_json::from_str(fixture).unwrap() };
+
+    let published = load(include_str!("../fixtures/webhooks/release_published.json"));
+    assert_eq!(published.release_action(), ReleaseAction::Publish);
+    let target: WebhookTarget =
+        serde_json::from_str(include_str!("../fixtures/webhooks/release_published.json")).unwrap();
+    assert_eq!(target.repository.full_name, "Nadybot/EXPORT_MODULE");
+    assert_eq!(target.sender.id, 12345678);
+    assert_eq!(
+        published.release.zip_url("Nadybot/EXPORT_MODULE"),
+        Some("https://github.com/Nadybot/EXPORT_MODULE/releases/download/v1.2.0/EXPORT_MODULE-1.2.0.zip")
+    );
+    assert_eq!(published.release.zip_url("Nadybot/RAID_MODULE"), None);
+    let zipball = Release {
+        assets: Vec::new(),
+        zipball_url: Some(String::from("http://localhost:8080/")),
+        ..load(include_str!("../fixtures/webhooks/release_published.json")).release
+    };
+    assert_eq!(zipball.zip_url("Nadybot/EXPORT_MODULE"), None);
+    assert!(published
+        .release
+        .check_version(&Version::new(1, 2, 0))
+        .is_ok());
+
+    let edited = load(include_str!("../fixtures/webhooks/release_edited.json"));
+    assert_eq!(edited.release_action(), ReleaseAction::UpdateNotes);
+    assert_eq!(
+        edited.release.notes().unwrap(),
+        "## Added\n\n- Export of raid points\n- Import of raid points"
+    );
+
+    let unpublished = load(include_str!(
+        "../fixtures/webhooks/release_unpublished.json"
+    ));
+    assert_eq!(unpublished.release_action(), ReleaseAction::Yank);
+    assert_eq!(unpublished.release.version(), Some(Version::new(1, 2, 0)));
+
+    let deleted = load(include_str!("../fixtures/webhooks/release_deleted.json"));
+    assert_eq!(deleted.release_action(), ReleaseAction::Yank);
+    assert_eq!(deleted.release.version(), Some(Version::new(1, 2, 0)));
+
+    let deleted_draft = GithubReleaseWebhook {
+        release: Release {
+            draft: true,
+            ..deleted.release
+        },
+        ..deleted
+    };
+    assert_eq!(deleted_draft.release_action(), ReleaseAction::Ignore);
 }